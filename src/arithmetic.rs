@@ -0,0 +1,580 @@
+//! Integer arithmetic for `$((...))`, `((...))` and `let`: the operators of C
+//! with their precedence, on 64-bit integers that wrap around on overflow.
+
+use std::{collections::HashMap, fmt};
+
+/// How deep variables whose values are expressions themselves may refer to
+/// each other, which stops `x=x` from recursing forever.
+const MAX_DEPTH: usize = 128;
+
+/// The symbols of arithmetic expressions, from `<<=` down to parentheses. The
+/// tokenizer takes the first that the input starts with, so `**` and the
+/// compound assignments are listed ahead of the single characters they begin
+/// with.
+const OPERATORS: [&str; 39] = [
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=",
+    "*=", "/=", "%=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "&", "|", "^", "~",
+    "!", "=", "?", ":", ",", "(", ")",
+];
+
+const ASSIGNMENT_OPERATORS: [&str; 11] = [
+    "=", "+=", "-=", "*=", "/=", "%=", "<<=", ">>=", "&=", "^=", "|=",
+];
+
+/// The binary operators from the loosest to the tightest binding, all of them
+/// associating to the left.
+const BINARY_OPERATORS: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<=", ">=", "<", ">"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+/// An error in an expression, with the text from where it was found on.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ArithmeticError {
+    pub message: String,
+    pub token: String,
+}
+
+impl fmt::Display for ArithmeticError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (error token is \"{}\")", self.message, self.token)
+    }
+}
+
+/// Evaluates an expression whose parameters and substitutions are already
+/// expanded. Names refer to shell variables: an unset or empty one counts as
+/// 0, and the value of any other is evaluated as an expression itself.
+/// Assignments and increments store their results back into the variables.
+pub fn evaluate(
+    expression: &str,
+    variables: &mut HashMap<String, String>,
+) -> Result<i64, ArithmeticError> {
+    evaluate_at_depth(expression, variables, 0)
+}
+
+fn evaluate_at_depth(
+    expression: &str,
+    variables: &mut HashMap<String, String>,
+    depth: usize,
+) -> Result<i64, ArithmeticError> {
+    let tokens = tokenize(expression)?;
+    let mut parser = Parser {
+        input: expression,
+        tokens,
+        pos: 0,
+    };
+    if parser.tokens.is_empty() {
+        return Ok(0);
+    }
+    let expr = parser.comma()?;
+    if parser.pos < parser.tokens.len() {
+        return Err(parser.error("syntax error in expression"));
+    }
+    Evaluator {
+        input: expression,
+        variables,
+        depth,
+    }
+    .eval(&expr)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TokenKind {
+    Number(i64),
+    Name(String),
+    Operator(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Token {
+    kind: TokenKind,
+    /// Byte offset of the token in the expression.
+    start: usize,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, ArithmeticError> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut pos = 0;
+    while let Some(c) = input[pos..].chars().next() {
+        let start = pos;
+        let rest = &input[pos..];
+        let kind = if c.is_whitespace() {
+            pos += c.len_utf8();
+            continue;
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            pos += len;
+            TokenKind::Number(number(&rest[..len]).ok_or_else(|| ArithmeticError {
+                message: String::from("value too great for base"),
+                token: rest[..len].to_string(),
+            })?)
+        } else if c == '_' || c.is_ascii_alphabetic() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            pos += len;
+            TokenKind::Name(rest[..len].to_string())
+        } else {
+            let Some(&operator) = OPERATORS.iter().find(|operator| rest.starts_with(**operator))
+            else {
+                return Err(ArithmeticError {
+                    message: String::from("syntax error: invalid arithmetic operator"),
+                    token: rest.to_string(),
+                });
+            };
+            // `++` and `--` only count as such next to a name they can change,
+            // so that `1--2` is 1 minus -2.
+            let next_to_name = || {
+                let after_name = matches!(
+                    tokens.last(),
+                    Some(Token {
+                        kind: TokenKind::Name(_),
+                        ..
+                    })
+                );
+                let rest = rest[operator.len()..].trim_start();
+                after_name || rest.starts_with(|c: char| c == '_' || c.is_ascii_alphabetic())
+            };
+            let operator = match operator {
+                "++" | "--" if !next_to_name() => &operator[..1],
+                operator => operator,
+            };
+            pos += operator.len();
+            TokenKind::Operator(operator)
+        };
+        tokens.push(Token { kind, start });
+    }
+    Ok(tokens)
+}
+
+/// Reads an integer literal: hexadecimal after `0x`, octal after `0`, decimal
+/// otherwise.
+fn number(literal: &str) -> Option<i64> {
+    let (digits, radix) = if let Some(hex) = literal
+        .strip_prefix("0x")
+        .or_else(|| literal.strip_prefix("0X"))
+    {
+        (hex, 16)
+    } else if literal.len() > 1 && literal.starts_with('0') {
+        (&literal[1..], 8)
+    } else {
+        (literal, 10)
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    digits.chars().try_fold(0i64, |value, c| {
+        let digit = c.to_digit(radix)?;
+        Some(value.wrapping_mul(radix as i64).wrapping_add(digit as i64))
+    })
+}
+
+#[derive(Debug)]
+enum Expr {
+    Number(i64),
+    Variable(String),
+    Unary(&'static str, Box<Expr>),
+    /// A binary operator, with where its right operand starts to point errors
+    /// such as a division by zero at it.
+    Binary(&'static str, Box<Expr>, Box<Expr>, usize),
+    /// `name op= value`
+    Assign(String, &'static str, Box<Expr>, usize),
+    /// `++name`, `--name`, `name++` or `name--`: adds `delta` to the variable
+    /// and gives its value from before if `postfix`, after otherwise.
+    Increment { name: String, delta: i64, postfix: bool },
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+/// Builds the tree for an expression by recursive descent, one method per
+/// level of precedence.
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+type ParseResult = Result<Expr, ArithmeticError>;
+
+impl Parser<'_> {
+    fn peek_operator(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token {
+                kind: TokenKind::Operator(operator),
+                ..
+            }) => Some(operator),
+            _ => None,
+        }
+    }
+
+    /// Where the current token starts, or the end of the input after the last.
+    fn start(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .map_or(self.input.len(), |token| token.start)
+    }
+
+    /// An error at the current token, or at the last one if the expression
+    /// ended early.
+    fn error(&self, message: &str) -> ArithmeticError {
+        let start = match self.tokens.get(self.pos) {
+            Some(token) => token.start,
+            None => self.tokens.last().map_or(0, |token| token.start),
+        };
+        ArithmeticError {
+            message: message.to_string(),
+            token: self.input[start..].to_string(),
+        }
+    }
+
+    fn expect(&mut self, operator: &str) -> Result<(), ArithmeticError> {
+        if self.peek_operator() != Some(operator) {
+            return Err(self.error(&format!("syntax error: `{}' expected", operator)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn comma(&mut self) -> ParseResult {
+        let mut left = self.assignment()?;
+        while self.peek_operator() == Some(",") {
+            self.pos += 1;
+            let start = self.start();
+            let right = self.assignment()?;
+            left = Expr::Binary(",", Box::new(left), Box::new(right), start);
+        }
+        Ok(left)
+    }
+
+    fn assignment(&mut self) -> ParseResult {
+        let left = self.conditional()?;
+        let Some(operator) = self
+            .peek_operator()
+            .filter(|operator| ASSIGNMENT_OPERATORS.contains(operator))
+        else {
+            return Ok(left);
+        };
+        let Expr::Variable(name) = left else {
+            return Err(self.error("attempted assignment to non-variable"));
+        };
+        self.pos += 1;
+        let start = self.start();
+        let value = self.assignment()?;
+        Ok(Expr::Assign(name, operator, Box::new(value), start))
+    }
+
+    fn conditional(&mut self) -> ParseResult {
+        let condition = self.binary(0)?;
+        if self.peek_operator() != Some("?") {
+            return Ok(condition);
+        }
+        self.pos += 1;
+        let then = self.comma()?;
+        self.expect(":")?;
+        let otherwise = self.conditional()?;
+        Ok(Expr::Conditional(
+            Box::new(condition),
+            Box::new(then),
+            Box::new(otherwise),
+        ))
+    }
+
+    fn binary(&mut self, level: usize) -> ParseResult {
+        let Some(operators) = BINARY_OPERATORS.get(level) else {
+            return self.power();
+        };
+        let mut left = self.binary(level + 1)?;
+        while let Some(operator) = self
+            .peek_operator()
+            .filter(|operator| operators.contains(operator))
+        {
+            self.pos += 1;
+            let start = self.start();
+            let right = self.binary(level + 1)?;
+            left = Expr::Binary(operator, Box::new(left), Box::new(right), start);
+        }
+        Ok(left)
+    }
+
+    /// `**`, which associates to the right and binds tighter than the other
+    /// binary operators, but looser than the unary ones.
+    fn power(&mut self) -> ParseResult {
+        let base = self.unary()?;
+        if self.peek_operator() != Some("**") {
+            return Ok(base);
+        }
+        self.pos += 1;
+        let start = self.start();
+        let exponent = self.power()?;
+        Ok(Expr::Binary("**", Box::new(base), Box::new(exponent), start))
+    }
+
+    fn unary(&mut self) -> ParseResult {
+        match self.peek_operator() {
+            Some(operator @ ("-" | "+" | "!" | "~")) => {
+                self.pos += 1;
+                Ok(Expr::Unary(operator, Box::new(self.unary()?)))
+            }
+            Some(operator @ ("++" | "--")) => {
+                self.pos += 1;
+                let Some(Token {
+                    kind: TokenKind::Name(name),
+                    ..
+                }) = self.tokens.get(self.pos).cloned()
+                else {
+                    return Err(self.error("syntax error: operand expected"));
+                };
+                self.pos += 1;
+                Ok(Expr::Increment {
+                    name,
+                    delta: if operator == "++" { 1 } else { -1 },
+                    postfix: false,
+                })
+            }
+            _ => self.postfix(),
+        }
+    }
+
+    fn postfix(&mut self) -> ParseResult {
+        let operand = self.primary()?;
+        match (operand, self.peek_operator()) {
+            (Expr::Variable(name), Some(operator @ ("++" | "--"))) => {
+                self.pos += 1;
+                Ok(Expr::Increment {
+                    name,
+                    delta: if operator == "++" { 1 } else { -1 },
+                    postfix: true,
+                })
+            }
+            (operand, _) => Ok(operand),
+        }
+    }
+
+    fn primary(&mut self) -> ParseResult {
+        let Some(token) = self.tokens.get(self.pos).cloned() else {
+            return Err(self.error("syntax error: operand expected"));
+        };
+        match token.kind {
+            TokenKind::Number(value) => {
+                self.pos += 1;
+                Ok(Expr::Number(value))
+            }
+            TokenKind::Name(name) => {
+                self.pos += 1;
+                Ok(Expr::Variable(name))
+            }
+            TokenKind::Operator("(") => {
+                self.pos += 1;
+                let expr = self.comma()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            TokenKind::Operator(_) => Err(self.error("syntax error: operand expected")),
+        }
+    }
+}
+
+struct Evaluator<'a> {
+    input: &'a str,
+    variables: &'a mut HashMap<String, String>,
+    depth: usize,
+}
+
+impl Evaluator<'_> {
+    fn eval(&mut self, expr: &Expr) -> Result<i64, ArithmeticError> {
+        Ok(match expr {
+            Expr::Number(value) => *value,
+            Expr::Variable(name) => self.variable(name)?,
+            Expr::Unary(operator, operand) => {
+                let value = self.eval(operand)?;
+                match *operator {
+                    "-" => value.wrapping_neg(),
+                    "!" => (value == 0) as i64,
+                    "~" => !value,
+                    _ => value,
+                }
+            }
+            Expr::Binary("&&", left, right, _) => {
+                (self.eval(left)? != 0 && self.eval(right)? != 0) as i64
+            }
+            Expr::Binary("||", left, right, _) => {
+                (self.eval(left)? != 0 || self.eval(right)? != 0) as i64
+            }
+            Expr::Binary(operator, left, right, start) => {
+                let left = self.eval(left)?;
+                let right = self.eval(right)?;
+                self.apply(operator, left, right, *start)?
+            }
+            Expr::Assign(name, operator, value, start) => {
+                let value = self.eval(value)?;
+                let value = match operator.strip_suffix('=').filter(|op| !op.is_empty()) {
+                    Some(operator) => {
+                        let current = self.variable(name)?;
+                        self.apply(operator, current, value, *start)?
+                    }
+                    None => value,
+                };
+                self.variables.insert(name.clone(), value.to_string());
+                value
+            }
+            Expr::Increment {
+                name,
+                delta,
+                postfix,
+            } => {
+                let value = self.variable(name)?;
+                let changed = value.wrapping_add(*delta);
+                self.variables.insert(name.clone(), changed.to_string());
+                if *postfix {
+                    value
+                } else {
+                    changed
+                }
+            }
+            Expr::Conditional(condition, then, otherwise) => {
+                if self.eval(condition)? != 0 {
+                    self.eval(then)?
+                } else {
+                    self.eval(otherwise)?
+                }
+            }
+        })
+    }
+
+    fn variable(&mut self, name: &str) -> Result<i64, ArithmeticError> {
+        let value = self.variables.get(name).cloned().unwrap_or_default();
+        if value.trim().is_empty() {
+            return Ok(0);
+        }
+        if self.depth >= MAX_DEPTH {
+            return Err(ArithmeticError {
+                message: String::from("expression recursion level exceeded"),
+                token: value,
+            });
+        }
+        evaluate_at_depth(&value, self.variables, self.depth + 1)
+    }
+
+    fn apply(
+        &self,
+        operator: &str,
+        left: i64,
+        right: i64,
+        start: usize,
+    ) -> Result<i64, ArithmeticError> {
+        let error = |message: &str| ArithmeticError {
+            message: message.to_string(),
+            token: self.input[start..].to_string(),
+        };
+        Ok(match operator {
+            "," => right,
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" | "%" if right == 0 => return Err(error("division by 0")),
+            "/" => left.wrapping_div(right),
+            "%" => left.wrapping_rem(right),
+            "**" if right < 0 => return Err(error("exponent less than 0")),
+            "**" => power(left, right),
+            "<<" => left.wrapping_shl(right as u32),
+            ">>" => left.wrapping_shr(right as u32),
+            "&" => left & right,
+            "|" => left | right,
+            "^" => left ^ right,
+            "==" => (left == right) as i64,
+            "!=" => (left != right) as i64,
+            "<" => (left < right) as i64,
+            "<=" => (left <= right) as i64,
+            ">" => (left > right) as i64,
+            ">=" => (left >= right) as i64,
+            _ => unreachable!("unknown operator {}", operator),
+        })
+    }
+}
+
+/// Raises `base` to a non-negative `exponent` by repeated squaring, wrapping
+/// around on overflow.
+fn power(mut base: i64, mut exponent: i64) -> i64 {
+    let mut result: i64 = 1;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result.wrapping_mul(base);
+        }
+        base = base.wrapping_mul(base);
+        exponent >>= 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluate_should_follow_c_precedence_and_update_variables() {
+        let mut variables = HashMap::from([
+            (String::from("x"), String::from("3")),
+            (String::from("expr"), String::from("x + 1")),
+            (String::from("empty"), String::new()),
+        ]);
+        let test_cases = vec![
+            ("", 0),
+            ("1 + 2 * 3", 7),
+            ("(1 + 2) * 3", 9),
+            ("2 ** 3 ** 2", 512),
+            ("-2 ** 2", 4),
+            ("7 % -3", 1),
+            ("1 - -2", 3),
+            ("1--2", 3),
+            ("010 + 0x1f", 39),
+            ("1 << 62 << 1", i64::MIN),
+            ("~0", -1),
+            ("!5 || 3 > 2 && 4 != 4", 0),
+            ("1 ? 2 : 3", 2),
+            ("0 ? 2 : 0 ? 3 : 4", 4),
+            ("unset + empty", 0),
+            ("expr * 2", 8),
+            ("x += 2", 5),
+            ("x++", 5),
+            ("x", 6),
+            ("--x", 5),
+            ("y = z = 2, y * z", 4),
+            ("0 && (w = 1)", 0),
+            ("w", 0),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(evaluate(test_case, &mut variables), Ok(expected_result), "{}", test_case);
+        }
+    }
+
+    #[test]
+    fn evaluate_should_point_errors_at_the_offending_token() {
+        let mut variables = HashMap::from([(String::from("loop"), String::from("loop"))]);
+        let test_cases = vec![
+            ("1 / 0", "division by 0", "0"),
+            ("5 % (2 - 2)", "division by 0", "(2 - 2)"),
+            ("2 ** -1", "exponent less than 0", "-1"),
+            ("09", "value too great for base", "09"),
+            ("1 +", "syntax error: operand expected", "+"),
+            ("3 4", "syntax error in expression", "4"),
+            ("3 = 4", "attempted assignment to non-variable", "= 4"),
+            ("(1", "syntax error: `)' expected", "1"),
+            ("1 $ 2", "syntax error: invalid arithmetic operator", "$ 2"),
+            ("loop", "expression recursion level exceeded", "loop"),
+        ];
+        for (test_case, message, token) in test_cases {
+            let err = evaluate(test_case, &mut variables).unwrap_err();
+            let err = (err.message.as_str(), err.token.as_str());
+            assert_eq!(err, (message, token), "{}", test_case);
+        }
+    }
+}
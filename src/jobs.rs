@@ -0,0 +1,411 @@
+//! Job control: the job table, holding commands started in the background
+//! with `&` and commands that were stopped, and the process groups and
+//! terminal ownership that let the user suspend and resume them.
+
+use std::{fmt, io};
+
+use libc::pid_t;
+
+use crate::traps;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Running,
+    Stopped,
+    /// Finished with the given exit status.
+    Done(i32),
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobState::Running => f.write_str("Running"),
+            JobState::Stopped => f.write_str("Stopped"),
+            JobState::Done(0) => f.write_str("Done"),
+            JobState::Done(status) => write!(f, "Exit {}", status),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Job {
+    pub id: usize,
+    /// Process ID of the first process of the job.
+    pub pid: pid_t,
+    /// Process group of the job, or `None` if it shares the shell's group, as
+    /// it does without job control.
+    pub pgid: Option<pid_t>,
+    /// Processes of the job that haven't finished yet.
+    processes: Vec<pid_t>,
+    /// The process whose status becomes the status of the job, the last one of
+    /// a pipeline.
+    last: pid_t,
+    status: i32,
+    /// The command line the job runs, as it was typed.
+    pub command: String,
+    pub state: JobState,
+}
+
+impl Job {
+    /// A running job made of the given processes, the last of which determines
+    /// its status.
+    pub fn new(processes: Vec<pid_t>, pgid: Option<pid_t>, command: String) -> Self {
+        Job {
+            id: 0,
+            pid: processes.first().copied().unwrap_or_default(),
+            pgid,
+            last: processes.last().copied().unwrap_or_default(),
+            processes,
+            status: 0,
+            command,
+            state: JobState::Running,
+        }
+    }
+
+    /// Sends a signal to every process of the job.
+    pub fn signal(&self, signal: libc::c_int) {
+        match self.pgid {
+            Some(pgid) => unsafe {
+                libc::kill(-pgid, signal);
+            },
+            None => {
+                for &pid in &self.processes {
+                    unsafe { libc::kill(pid, signal) };
+                }
+            }
+        }
+    }
+
+    /// Collects the changes of state of the job's processes. With `block`,
+    /// waits until the job finishes or stops, otherwise only looks at what
+    /// already happened.
+    pub fn wait(&mut self, block: bool) -> io::Result<()> {
+        let mut flags = libc::WUNTRACED | libc::WCONTINUED;
+        if !block {
+            flags |= libc::WNOHANG;
+        }
+        while let Some(&pid) = self.processes.first() {
+            let mut status = 0;
+            match unsafe { libc::waitpid(pid, &mut status, flags) } {
+                -1 => {
+                    let err = io::Error::last_os_error();
+                    match err.raw_os_error() {
+                        Some(libc::EINTR) => continue,
+                        // Someone else reaped it, its status is lost.
+                        Some(libc::ECHILD) => status = 0,
+                        _ => return Err(err),
+                    }
+                }
+                0 => return Ok(()),
+                _ => (),
+            }
+            if libc::WIFSTOPPED(status) {
+                self.state = JobState::Stopped;
+                return Ok(());
+            }
+            if libc::WIFCONTINUED(status) {
+                self.state = JobState::Running;
+                continue;
+            }
+            self.processes.remove(0);
+            if pid == self.last {
+                self.status = if libc::WIFSIGNALED(status) {
+                    128 + libc::WTERMSIG(status)
+                } else {
+                    libc::WEXITSTATUS(status)
+                };
+            }
+        }
+        self.state = JobState::Done(self.status);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Jobs {
+    jobs: Vec<Job>,
+    /// Job ids from the least to the most recently started or stopped. The last
+    /// one is the current job, `%+`, and the one before it the previous, `%-`.
+    recent: Vec<usize>,
+}
+
+impl Jobs {
+    /// Adds a job and returns its id, one more than the highest id in use.
+    pub fn add(&mut self, mut job: Job) -> usize {
+        let id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        job.id = id;
+        self.jobs.push(job);
+        self.recent.push(id);
+        id
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Job> {
+        self.jobs.iter().find(|job| job.id == id)
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|job| job.id == id)
+    }
+
+    /// The job with the given process as its first or last process.
+    pub fn find_by_pid(&self, pid: pid_t) -> Option<&Job> {
+        self.jobs.iter().find(|job| job.pid == pid || job.last == pid)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Job> {
+        self.jobs.iter()
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Job> {
+        self.recent.retain(|&recent| recent != id);
+        let index = self.jobs.iter().position(|job| job.id == id)?;
+        Some(self.jobs.remove(index))
+    }
+
+    pub fn set_state(&mut self, id: usize, state: JobState) {
+        if let Some(job) = self.jobs.iter_mut().find(|job| job.id == id) {
+            job.state = state;
+            if state == JobState::Stopped {
+                self.recent.retain(|&recent| recent != id);
+                self.recent.push(id);
+            }
+        }
+    }
+
+    /// `+` for the current job, `-` for the previous one and a space otherwise.
+    pub fn marker(&self, id: usize) -> char {
+        match self.recent.iter().rev().position(|&recent| recent == id) {
+            Some(0) => '+',
+            Some(1) => '-',
+            _ => ' ',
+        }
+    }
+
+    /// Describes a job the way `jobs` lists it, with its process ID if `long`.
+    pub fn describe(&self, job: &Job, long: bool) -> String {
+        let pid = if long { format!(" {} ", job.pid) } else { String::from("  ") };
+        let background = if job.state == JobState::Running { " &" } else { "" };
+        format!(
+            "[{}]{}{}{:<24}{}{}",
+            job.id,
+            self.marker(job.id),
+            pid,
+            job.state.to_string(),
+            job.command,
+            background
+        )
+    }
+
+    pub fn remove_finished(&mut self) {
+        let finished: Vec<usize> = self
+            .jobs
+            .iter()
+            .filter(|job| matches!(job.state, JobState::Done(_)))
+            .map(|job| job.id)
+            .collect();
+        for id in finished {
+            self.remove(id);
+        }
+    }
+
+    /// Describes the jobs that finished or stopped since they were last looked
+    /// at, and forgets the finished ones.
+    pub fn notify(&mut self) -> Vec<String> {
+        let notices = self
+            .update()
+            .into_iter()
+            .filter_map(|id| self.get(id))
+            .map(|job| self.describe(job, false))
+            .collect();
+        self.remove_finished();
+        notices
+    }
+
+    /// Finds the job a job spec refers to: `%N` by its id, `%%` or `%+` the
+    /// current job, `%-` the previous one and `%prefix` the job whose command
+    /// starts with the prefix. Without a spec, the current job.
+    pub fn resolve(&self, spec: Option<&str>) -> Result<usize, String> {
+        let spec = spec.unwrap_or("%+");
+        let current = |offset: usize| {
+            self.recent
+                .iter()
+                .rev()
+                .nth(offset)
+                .copied()
+                .ok_or_else(|| format!("{}: no such job", spec))
+        };
+        match spec.strip_prefix('%') {
+            Some("%" | "+" | "") => current(0),
+            Some("-") => current(1),
+            Some(id) if id.bytes().all(|b| b.is_ascii_digit()) => id
+                .parse()
+                .ok()
+                .filter(|&id| self.get(id).is_some())
+                .ok_or_else(|| format!("{}: no such job", spec)),
+            Some(prefix) => {
+                let mut matches = self.jobs.iter().filter(|job| job.command.starts_with(prefix));
+                match (matches.next(), matches.next()) {
+                    (Some(job), None) => Ok(job.id),
+                    (Some(_), Some(_)) => Err(format!("{}: ambiguous job spec", spec)),
+                    (None, _) => Err(format!("{}: no such job", spec)),
+                }
+            }
+            None => Err(format!("{}: no such job", spec)),
+        }
+    }
+
+    /// Checks every unfinished job for a change of state without waiting, and
+    /// returns the ids of those that finished or stopped.
+    pub fn update(&mut self) -> Vec<usize> {
+        let mut changed = Vec::new();
+        for job in &mut self.jobs {
+            if matches!(job.state, JobState::Done(_)) {
+                continue;
+            }
+            let state = job.state;
+            if job.wait(false).is_ok() && job.state != state && job.state != JobState::Running {
+                changed.push(job.id);
+            }
+        }
+        for &id in &changed {
+            if self.get(id).is_some_and(|job| job.state == JobState::Stopped) {
+                self.recent.retain(|&recent| recent != id);
+                self.recent.push(id);
+            }
+        }
+        changed
+    }
+}
+
+/// Signals that would stop or kill an interactive shell, but should reach the
+/// jobs it runs instead.
+pub const JOB_CONTROL_SIGNALS: [libc::c_int; 4] =
+    [libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU, libc::SIGQUIT];
+
+/// Turns on job control if the shell reads from a terminal: puts the shell in a
+/// process group of its own in the foreground of the terminal, and ignores the
+/// signals the terminal sends to foreground jobs. Returns whether it did.
+pub fn enable_job_control() -> bool {
+    if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 {
+        return false;
+    }
+    // Started in the background, wait until brought to the foreground.
+    loop {
+        let pgrp = unsafe { libc::getpgrp() };
+        if unsafe { libc::tcgetpgrp(libc::STDIN_FILENO) } == pgrp {
+            break;
+        }
+        unsafe { libc::kill(-pgrp, libc::SIGTTIN) };
+    }
+    for signal in JOB_CONTROL_SIGNALS {
+        unsafe { libc::signal(signal, libc::SIG_IGN) };
+    }
+    let pid = unsafe { libc::getpid() };
+    if unsafe { libc::getpgrp() } != pid && unsafe { libc::setpgid(0, 0) } == -1 {
+        return false;
+    }
+    give_terminal_to(pid);
+    true
+}
+
+/// Hands the terminal to a process group, which makes it the foreground job.
+pub fn give_terminal_to(pgid: pid_t) {
+    unsafe { libc::tcsetpgrp(libc::STDIN_FILENO, pgid) };
+}
+
+/// Takes the terminal back for the shell.
+pub fn reclaim_terminal() {
+    give_terminal_to(unsafe { libc::getpgrp() });
+}
+
+/// Moves a newly forked child into the process group of its job, a new one if
+/// `pgid` is 0, hands it the terminal for a foreground job, and restores the
+/// signals the shell ignores, except those ignored with `trap`. Only calls
+/// async-signal-safe functions, so it can run between fork and exec.
+pub fn enter_job(pgid: pid_t, foreground: bool) -> io::Result<()> {
+    if unsafe { libc::setpgid(0, pgid) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if foreground {
+        give_terminal_to(unsafe { libc::getpgrp() });
+    }
+    for signal in JOB_CONTROL_SIGNALS {
+        if !traps::is_ignored(signal) {
+            unsafe { libc::signal(signal, libc::SIG_DFL) };
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{process::Command, thread, time::Duration};
+
+    fn spawn(program: &str, args: &[&str]) -> pid_t {
+        Command::new(program).args(args).spawn().unwrap().id() as pid_t
+    }
+
+    #[test]
+    fn wait_should_follow_the_job_through_stop_continue_and_exit() {
+        let pid = spawn("sleep", &["5"]);
+        let mut job = Job::new(vec![pid], None, String::from("sleep 5"));
+        job.wait(false).unwrap();
+        assert_eq!(job.state, JobState::Running);
+        job.signal(libc::SIGSTOP);
+        job.wait(true).unwrap();
+        assert_eq!(job.state, JobState::Stopped);
+        job.signal(libc::SIGCONT);
+        // The continue is reported once the kernel resumes the process.
+        for _ in 0..100 {
+            job.wait(false).unwrap();
+            if job.state == JobState::Running {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(job.state, JobState::Running);
+        job.signal(libc::SIGTERM);
+        job.wait(true).unwrap();
+        assert_eq!(job.state, JobState::Done(128 + libc::SIGTERM));
+    }
+
+    #[test]
+    fn wait_should_take_the_status_of_the_last_process() {
+        let processes = vec![spawn("sh", &["-c", "exit 3"]), spawn("true", &[])];
+        let mut job = Job::new(processes, None, String::from("a | b"));
+        job.wait(true).unwrap();
+        assert_eq!(job.state, JobState::Done(0));
+        let processes = vec![spawn("true", &[]), spawn("sh", &["-c", "exit 3"])];
+        let mut job = Job::new(processes, None, String::from("a | b"));
+        job.wait(true).unwrap();
+        assert_eq!(job.state, JobState::Done(3));
+    }
+
+    #[test]
+    fn resolve_should_find_jobs_by_id_recency_and_command_prefix() {
+        let mut jobs = Jobs::default();
+        jobs.add(Job::new(vec![100], None, String::from("sleep 10")));
+        jobs.add(Job::new(vec![200, 201], Some(200), String::from("make all")));
+        jobs.add(Job::new(vec![300], None, String::from("sleep 20")));
+        let test_cases = vec![
+            (None, Ok(3)),
+            (Some("%%"), Ok(3)),
+            (Some("%+"), Ok(3)),
+            (Some("%-"), Ok(2)),
+            (Some("%1"), Ok(1)),
+            (Some("%make"), Ok(2)),
+            (Some("%sleep"), Err(String::from("%sleep: ambiguous job spec"))),
+            (Some("%4"), Err(String::from("%4: no such job"))),
+            (Some("1"), Err(String::from("1: no such job"))),
+        ];
+        for (spec, expected_result) in test_cases {
+            assert_eq!(jobs.resolve(spec), expected_result);
+        }
+        jobs.set_state(1, JobState::Stopped);
+        assert_eq!((jobs.marker(1), jobs.marker(3), jobs.marker(2)), ('+', '-', ' '));
+        jobs.remove(1);
+        assert_eq!(jobs.resolve(None), Ok(3));
+        assert_eq!(jobs.find_by_pid(201).map(|job| job.id), Some(2));
+    }
+}
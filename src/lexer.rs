@@ -0,0 +1,789 @@
+//! Splits a command line into the tokens of the shell grammar.
+
+use std::{fmt, iter::Peekable, mem, os::fd::RawFd, str::Chars};
+
+/// Byte range of a token in the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    /// A word, with its quotes and backslashes still in place so that they can
+    /// be honoured when it is expanded.
+    Word(String),
+    /// The descriptor number written right in front of a redirection operator.
+    IoNumber(RawFd),
+    Operator(Operator),
+    /// The expression of a `((...))` arithmetic command.
+    Arithmetic(String),
+    Newline,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenKind::Word(word) => f.write_str(word),
+            TokenKind::IoNumber(fd) => write!(f, "{}", fd),
+            TokenKind::Operator(operator) => f.write_str(operator.as_str()),
+            TokenKind::Arithmetic(expression) => write!(f, "(({}))", expression),
+            TokenKind::Newline => f.write_str("newline"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Pipe,
+    OrIf,
+    AndIf,
+    Semicolon,
+    Less,
+    DoubleLess,
+    DoubleLessDash,
+    TripleLess,
+    LessAnd,
+    LessGreat,
+    Great,
+    DoubleGreat,
+    GreatAnd,
+    Clobber,
+    AndGreat,
+    AndDoubleGreat,
+    Background,
+}
+
+/// Every operator, with the longer ones first so that they win over their
+/// prefixes.
+const OPERATORS: [(&str, Operator); 17] = [
+    ("<<<", Operator::TripleLess),
+    ("<<-", Operator::DoubleLessDash),
+    ("&>>", Operator::AndDoubleGreat),
+    ("||", Operator::OrIf),
+    ("&&", Operator::AndIf),
+    ("<<", Operator::DoubleLess),
+    ("<&", Operator::LessAnd),
+    ("<>", Operator::LessGreat),
+    (">>", Operator::DoubleGreat),
+    (">&", Operator::GreatAnd),
+    (">|", Operator::Clobber),
+    ("&>", Operator::AndGreat),
+    ("|", Operator::Pipe),
+    (";", Operator::Semicolon),
+    ("<", Operator::Less),
+    (">", Operator::Great),
+    ("&", Operator::Background),
+];
+
+impl Operator {
+    pub fn as_str(self) -> &'static str {
+        OPERATORS
+            .iter()
+            .find(|(_, operator)| *operator == self)
+            .map_or("", |(text, _)| text)
+    }
+
+    pub fn is_redirection(self) -> bool {
+        !matches!(
+            self,
+            Operator::Pipe
+                | Operator::OrIf
+                | Operator::AndIf
+                | Operator::Semicolon
+                | Operator::Background
+        )
+    }
+}
+
+/// An error in the input, located by the span of the offending text.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl SyntaxError {
+    /// Describes the error with its line and column in the input, followed by
+    /// that line with a caret under the offending text. Lines are numbered from
+    /// `first_line`, the number of the input's first line in its file.
+    pub fn diagnostic(&self, input: &str, first_line: usize) -> String {
+        let start = self.span.start.min(input.len());
+        let line_start = input[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = input[start..].find('\n').map_or(input.len(), |i| start + i);
+        let line = &input[line_start..line_end];
+        let prefix = &input[line_start..start];
+        // Tabs are kept so that the caret lines up however they are displayed.
+        let indent: String = prefix
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = input[start..self.span.end.clamp(start, line_end)].chars().count();
+        format!(
+            "line {}, column {}: {}\n{}\n{}{}",
+            first_line + input[..line_start].matches('\n').count(),
+            prefix.chars().count() + 1,
+            self.message,
+            line,
+            indent,
+            "^".repeat(width.max(1))
+        )
+    }
+
+    /// Whether the input ended in the middle of a command, which more lines
+    /// could complete. Errors about the input's final newline don't count.
+    pub fn is_incomplete(&self, input: &str) -> bool {
+        self.span.end == input.len() && !input[self.span.start..].starts_with('\n')
+    }
+}
+
+/// Splits the input into tokens, and sets aside the bodies of its
+/// here-documents, in the order they start. A body is made of the lines after
+/// the one with its `<<`, up to the line holding just its delimiter, and has no
+/// tokens.
+pub fn tokenize(input: &str) -> Result<(Vec<Token>, Vec<String>), SyntaxError> {
+    let mut lexer = Lexer::new(input);
+    let tokens = lexer.tokens()?;
+    Ok((tokens, lexer.bodies))
+}
+
+/// What the input leaves open at its end. The input still tokenizes, but
+/// someone reading a command line by line can take it as a command that goes
+/// on.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct OpenEnds {
+    /// A quote left open, as if it was closed at the end of the input.
+    pub quote: Option<SyntaxError>,
+    /// Whether the input ends with a backslash and a newline, which join the
+    /// next line to it.
+    pub backslash: bool,
+    /// The delimiter of a here-document whose body goes on to the end of the
+    /// input.
+    pub here_document: Option<String>,
+}
+
+/// Finds what the input leaves open. Input that doesn't tokenize has nothing
+/// open.
+pub fn open_ends(input: &str) -> OpenEnds {
+    let mut lexer = Lexer::new(input);
+    match lexer.tokens() {
+        Ok(_) => lexer.open_ends,
+        Err(_) => OpenEnds::default(),
+    }
+}
+
+struct Lexer<'a> {
+    input: &'a str,
+    pos: usize,
+    /// What is left open at the end, which is otherwise tolerated.
+    open_ends: OpenEnds,
+    /// The delimiters of the here-documents started on the current line, and
+    /// whether their bodies have leading tabs stripped.
+    pending: Vec<(String, bool)>,
+    bodies: Vec<String>,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(input: &'a str) -> Self {
+        Lexer {
+            input,
+            pos: 0,
+            open_ends: OpenEnds::default(),
+            pending: Vec::new(),
+            bodies: Vec::new(),
+        }
+    }
+
+    fn tokens(&mut self) -> Result<Vec<Token>, SyntaxError> {
+        let mut tokens = Vec::new();
+        while let Some(c) = self.peek() {
+            let start = self.pos;
+            let kind = match c {
+                ' ' | '\t' => {
+                    self.pos += 1;
+                    continue;
+                }
+                '\n' => {
+                    self.pos += 1;
+                    TokenKind::Newline
+                }
+                '#' => {
+                    self.skip_comment();
+                    continue;
+                }
+                '\\' if self.rest().starts_with("\\\n") => {
+                    self.join_lines();
+                    continue;
+                }
+                '(' if self.rest().starts_with("((") => self.arithmetic()?,
+                _ => match self.operator() {
+                    Some(operator) => TokenKind::Operator(operator),
+                    None => {
+                        let word = self.word()?;
+                        if !word.is_empty()
+                            && word.bytes().all(|b| b.is_ascii_digit())
+                            && self.at_redirection()
+                        {
+                            let fd = word.parse().map_err(|_| SyntaxError {
+                                message: format!("{}: bad file descriptor", word),
+                                span: Span {
+                                    start,
+                                    end: self.pos,
+                                },
+                            })?;
+                            TokenKind::IoNumber(fd)
+                        } else {
+                            TokenKind::Word(word)
+                        }
+                    }
+                },
+            };
+            if let (
+                TokenKind::Word(word),
+                Some(TokenKind::Operator(
+                    operator @ (Operator::DoubleLess | Operator::DoubleLessDash),
+                )),
+            ) = (&kind, tokens.last().map(|token: &Token| &token.kind))
+            {
+                let strip_tabs = *operator == Operator::DoubleLessDash;
+                self.pending.push((remove_quotes(word), strip_tabs));
+            }
+            let newline = kind == TokenKind::Newline;
+            tokens.push(Token {
+                kind,
+                span: Span {
+                    start,
+                    end: self.pos,
+                },
+            });
+            if newline {
+                self.here_document_bodies();
+            }
+        }
+        self.here_document_bodies();
+        Ok(tokens)
+    }
+
+    /// Reads the bodies of the here-documents started on the line just ended.
+    /// Ending the input early ends a body too.
+    fn here_document_bodies(&mut self) {
+        let input = self.input;
+        for (delimiter, strip_tabs) in mem::take(&mut self.pending) {
+            let mut body = String::new();
+            loop {
+                if self.pos == input.len() {
+                    self.open_ends.here_document = Some(delimiter);
+                    break;
+                }
+                let end = self.rest().find('\n').map_or(input.len(), |end| self.pos + end);
+                let line = &input[self.pos..end];
+                self.pos = input.len().min(end + 1);
+                let line = if strip_tabs {
+                    line.trim_start_matches('\t')
+                } else {
+                    line
+                };
+                if line == delimiter {
+                    break;
+                }
+                body.push_str(line);
+                body.push('\n');
+            }
+            self.bodies.push(body);
+        }
+    }
+
+    fn rest(&self) -> &str {
+        &self.input[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_comment(&mut self) {
+        self.pos = self.rest().find('\n').map_or(self.input.len(), |end| self.pos + end);
+    }
+
+    /// Skips a backslash and the newline after it, which join two lines.
+    fn join_lines(&mut self) {
+        self.pos += 2;
+        self.open_ends.backslash = self.pos == self.input.len();
+    }
+
+    fn operator(&mut self) -> Option<Operator> {
+        let &(text, operator) = OPERATORS.iter().find(|(text, _)| self.rest().starts_with(text))?;
+        self.pos += text.len();
+        Some(operator)
+    }
+
+    fn at_redirection(&self) -> bool {
+        self.rest().starts_with(['<', '>'])
+    }
+
+    /// Whether the word being read ends here, at a blank or an operator.
+    fn at_word_end(&self) -> bool {
+        match self.peek() {
+            None | Some(' ' | '\t' | '\n') => true,
+            Some(_) => OPERATORS.iter().any(|(text, _)| self.rest().starts_with(text)),
+        }
+    }
+
+    fn unterminated(&self, start: usize, closing: char) -> SyntaxError {
+        SyntaxError {
+            message: format!("unexpected EOF while looking for matching `{}'", closing),
+            span: Span {
+                start,
+                end: self.input.len(),
+            },
+        }
+    }
+
+    /// Reads a `((...))` arithmetic command, up to the `))` that closes it.
+    fn arithmetic(&mut self) -> Result<TokenKind, SyntaxError> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut chars = self.rest().chars().peekable();
+        let Ok(script) = read_substitution(&mut chars) else {
+            return Err(self.unterminated(start, ')'));
+        };
+        self.pos += script.len() + 1;
+        match arithmetic_expression(&script) {
+            Some(expression) => Ok(TokenKind::Arithmetic(expression.to_string())),
+            None => Err(SyntaxError {
+                message: String::from("syntax error near unexpected token `('"),
+                span: Span {
+                    start,
+                    end: start + 1,
+                },
+            }),
+        }
+    }
+
+    /// Reads a word up to the first unquoted blank or operator. A quote that
+    /// is never closed extends to the end of the input.
+    fn word(&mut self) -> Result<String, SyntaxError> {
+        let mut word = String::new();
+        let mut in_double_quote = false;
+        let mut quote_start = self.pos;
+        while (in_double_quote && self.peek().is_some()) || !self.at_word_end() {
+            let start = self.pos;
+            let Some(c) = self.bump() else {
+                break;
+            };
+            match c {
+                '\\' if self.peek() == Some('\n') => {
+                    self.pos -= 1;
+                    self.join_lines();
+                }
+                '\\' => match self.bump() {
+                    Some(next_char) => {
+                        word.push(c);
+                        word.push(next_char);
+                    }
+                    // A trailing backslash would continue the line, there is
+                    // nothing to escape.
+                    None if !in_double_quote => (),
+                    None => word.push(c),
+                },
+                '\'' if !in_double_quote => {
+                    let end = match self.rest().find('\'') {
+                        Some(end) => end + 1,
+                        None => {
+                            self.open_ends.quote = Some(self.unterminated(start, c));
+                            self.rest().len()
+                        }
+                    };
+                    word.push(c);
+                    word.push_str(&self.rest()[..end]);
+                    self.pos += end;
+                }
+                '"' => {
+                    word.push(c);
+                    in_double_quote = !in_double_quote;
+                    quote_start = start;
+                }
+                // Command substitutions belong to the word, whatever they contain.
+                '$' if self.peek() == Some('(') => {
+                    self.bump();
+                    let mut chars = self.rest().chars().peekable();
+                    let Ok(script) = read_substitution(&mut chars) else {
+                        return Err(self.unterminated(start, ')'));
+                    };
+                    word.push_str("$(");
+                    word.push_str(&script);
+                    word.push(')');
+                    self.pos += script.len() + 1;
+                }
+                '$' if self.peek() == Some('{') => {
+                    self.bump();
+                    let mut chars = self.rest().chars().peekable();
+                    let Ok(body) = read_braced(&mut chars, in_double_quote) else {
+                        return Err(self.unterminated(start, '}'));
+                    };
+                    word.push_str("${");
+                    word.push_str(&body);
+                    word.push('}');
+                    self.pos += body.len() + 1;
+                }
+                '`' => {
+                    let mut chars = self.rest().chars().peekable();
+                    let Ok(script) = read_backquoted(&mut chars) else {
+                        return Err(self.unterminated(start, '`'));
+                    };
+                    word.push(c);
+                    word.push_str(&script);
+                    word.push(c);
+                    self.pos += script.len() + 1;
+                }
+                _ => word.push(c),
+            }
+        }
+        if in_double_quote {
+            self.open_ends.quote = Some(self.unterminated(quote_start, '"'));
+        }
+        Ok(word)
+    }
+}
+
+/// Removes the quotes and escaping backslashes from a word without expanding it.
+pub fn remove_quotes(word: &str) -> String {
+    let mut unquoted = String::new();
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
+    let mut chars = word.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' if !in_double_quote => in_single_quote = !in_single_quote,
+            '"' if !in_single_quote => in_double_quote = !in_double_quote,
+            '\\' if in_double_quote => match chars.peek() {
+                Some('$' | '`' | '\\' | '"') => unquoted.extend(chars.next()),
+                _ => unquoted.push(c),
+            },
+            '\\' if !in_single_quote => unquoted.extend(chars.next()),
+            _ => unquoted.push(c),
+        }
+    }
+    unquoted
+}
+
+/// Reads the script of a `$(...)` command substitution that follows its opening
+/// parenthesis, and consumes the matching closing one. Parentheses that are
+/// quoted or belong to nested substitutions don't end it. If the input ends
+/// first, returns what was read as an error.
+pub fn read_substitution(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    let mut script = String::new();
+    let mut depth = 0;
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if !in_single_quote => {
+                script.push(c);
+                if let Some(next_char) = chars.next() {
+                    script.push(next_char);
+                }
+                continue;
+            }
+            '$' if !in_single_quote && chars.peek() == Some(&'(') => {
+                chars.next();
+                script.push_str("$(");
+                match read_substitution(chars) {
+                    Ok(nested) => {
+                        script.push_str(&nested);
+                        script.push(')');
+                    }
+                    Err(nested) => {
+                        script.push_str(&nested);
+                        return Err(script);
+                    }
+                }
+                continue;
+            }
+            '\'' if !in_double_quote => in_single_quote = !in_single_quote,
+            '"' if !in_single_quote => in_double_quote = !in_double_quote,
+            '(' if !in_single_quote && !in_double_quote => depth += 1,
+            ')' if !in_single_quote && !in_double_quote => {
+                if depth == 0 {
+                    return Ok(script);
+                }
+                depth -= 1;
+            }
+            _ => (),
+        }
+        script.push(c);
+    }
+    Err(script)
+}
+
+/// The expression of a `$((...))` arithmetic expansion, given the script
+/// `read_substitution` reads for it: one that is all in parentheses.
+pub fn arithmetic_expression(script: &str) -> Option<&str> {
+    let inner = script.strip_prefix('(')?;
+    let expression = read_substitution(&mut inner.chars().peekable()).ok()?;
+    // The parenthesis that closes the expression has to be the last one.
+    (expression.len() + 1 == inner.len()).then(|| &inner[..expression.len()])
+}
+
+/// Reads the script of a `` `...` `` command substitution up to the closing
+/// backquote, which is consumed. Escaped backquotes don't end it. If the input
+/// ends first, returns what was read as an error.
+pub fn read_backquoted(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    let mut script = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '`' => return Ok(script),
+            '\\' => {
+                script.push(c);
+                if let Some(next_char) = chars.next() {
+                    script.push(next_char);
+                }
+            }
+            _ => script.push(c),
+        }
+    }
+    Err(script)
+}
+
+/// Reads the inside of a `${...}` expansion, after its `{`, and consumes the
+/// matching `}`. Braces that are quoted, escaped or belong to nested
+/// expansions don't end it; single quotes only count outside double quotes. If
+/// the input ends first, returns what was read as an error.
+pub fn read_braced(
+    chars: &mut Peekable<Chars>,
+    in_double_quote: bool,
+) -> Result<String, String> {
+    let mut body = String::new();
+    let mut depth = 0;
+    let mut in_single_quote = false;
+    let mut in_inner_double_quote = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if !in_single_quote => {
+                body.push(c);
+                if let Some(next_char) = chars.next() {
+                    body.push(next_char);
+                }
+                continue;
+            }
+            '$' if !in_single_quote && chars.peek() == Some(&'(') => {
+                chars.next();
+                body.push_str("$(");
+                match read_substitution(chars) {
+                    Ok(nested) => {
+                        body.push_str(&nested);
+                        body.push(')');
+                    }
+                    Err(nested) => {
+                        body.push_str(&nested);
+                        return Err(body);
+                    }
+                }
+                continue;
+            }
+            '\'' if !in_double_quote && !in_inner_double_quote => {
+                in_single_quote = !in_single_quote
+            }
+            '"' if !in_single_quote => in_inner_double_quote = !in_inner_double_quote,
+            '{' if !in_single_quote && !in_inner_double_quote => depth += 1,
+            '}' if !in_single_quote && !in_inner_double_quote => {
+                if depth == 0 {
+                    return Ok(body);
+                }
+                depth -= 1;
+            }
+            _ => (),
+        }
+        body.push(c);
+    }
+    Err(body)
+}
+
+/// Whether `name` is a valid variable name: a letter or underscore followed by
+/// letters, digits and underscores.
+pub fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_should_return_typed_tokens_with_their_spans() {
+        let word = |word: &str| TokenKind::Word(String::from(word));
+        let test_cases = vec![
+            (
+                "echo \">\" 2>&1",
+                vec![
+                    (word("echo"), 0, 4),
+                    (word("\">\""), 5, 8),
+                    (TokenKind::IoNumber(2), 9, 10),
+                    (TokenKind::Operator(Operator::GreatAnd), 10, 12),
+                    (word("1"), 12, 13),
+                ],
+            ),
+            (
+                "a&&b # comment\n12>c",
+                vec![
+                    (word("a"), 0, 1),
+                    (TokenKind::Operator(Operator::AndIf), 1, 3),
+                    (word("b"), 3, 4),
+                    (TokenKind::Newline, 14, 15),
+                    (TokenKind::IoNumber(12), 15, 17),
+                    (TokenKind::Operator(Operator::Great), 17, 18),
+                    (word("c"), 18, 19),
+                ],
+            ),
+            (
+                "echo a#b 'x y'z&w",
+                vec![
+                    (word("echo"), 0, 4),
+                    (word("a#b"), 5, 8),
+                    (word("'x y'z"), 9, 15),
+                    (TokenKind::Operator(Operator::Background), 15, 16),
+                    (word("w"), 16, 17),
+                ],
+            ),
+            ("echo 'a b", vec![(word("echo"), 0, 4), (word("'a b"), 5, 9)]),
+            (
+                "((x = (1)))|y",
+                vec![
+                    (TokenKind::Arithmetic(String::from("x = (1)")), 0, 11),
+                    (TokenKind::Operator(Operator::Pipe), 11, 12),
+                    (word("y"), 12, 13),
+                ],
+            ),
+        ];
+        for (test_case, expected_result) in test_cases {
+            let expected_result: Vec<Token> = expected_result
+                .into_iter()
+                .map(|(kind, start, end)| Token {
+                    kind,
+                    span: Span { start, end },
+                })
+                .collect();
+            assert_eq!(tokenize(test_case), Ok((expected_result, vec![])));
+        }
+    }
+
+    #[test]
+    fn tokenize_should_set_here_document_bodies_aside() {
+        let test_cases = vec![
+            ("cat <<A <<-'B'\na\nA\n\tb\n\tB\necho", 7, vec!["a\n", "b\n"]),
+            ("cat <<A\n<<B\nA\n", 4, vec!["<<B\n"]),
+            ("cat <<A\na\n", 4, vec!["a\n"]),
+            // The body of a here-document in a substitution is only set aside
+            // when the substitution is run.
+            ("x=$(cat <<A\na\nA\n)\n", 2, vec![]),
+        ];
+        for (test_case, tokens, bodies) in test_cases {
+            let bodies = bodies.into_iter().map(String::from).collect();
+            let result = tokenize(test_case).map(|(tokens, bodies)| (tokens.len(), bodies));
+            assert_eq!(result, Ok((tokens, bodies)), "{:?}", test_case);
+        }
+    }
+
+    #[test]
+    fn diagnostic_should_show_the_line_and_a_caret_under_the_span() {
+        let err = SyntaxError {
+            message: String::from("syntax error near unexpected token `&&'"),
+            span: Span { start: 13, end: 15 },
+        };
+        assert_eq!(
+            err.diagnostic("echo a\n\tb && && c", 1),
+            "line 2, column 7: syntax error near unexpected token `&&'\n\tb && && c\n\t     ^^"
+        );
+        let err = SyntaxError {
+            message: String::from("syntax error near unexpected token `newline'"),
+            span: Span { start: 6, end: 6 },
+        };
+        assert_eq!(
+            err.diagnostic("echo >", 1),
+            "line 1, column 7: syntax error near unexpected token `newline'\necho >\n      ^"
+        );
+        assert_eq!(
+            err.diagnostic("echo >", 12),
+            "line 12, column 7: syntax error near unexpected token `newline'\necho >\n      ^"
+        );
+    }
+
+    #[test]
+    fn open_ends_and_is_incomplete_should_tell_commands_that_go_on() {
+        let test_cases = vec![
+            ("echo \"a\n", true),
+            ("echo 'a\n", true),
+            ("echo \"a\" 'b'\n", false),
+            ("echo $(a\n", true),
+            ("ls &&\n", true),
+            ("ls |\n", true),
+            ("echo >\n", false),
+            ("ls ;;\n", false),
+            ("while true; do\n", true),
+            ("if a; then b; fi\n", false),
+            ("echo one \\\n", true),
+            ("echo \"one\\\n", true),
+            ("echo one\\\\\n", false),
+            ("echo one \\\ntwo\n", false),
+            ("cat <<A\na\n", true),
+            ("cat <<A\na\nA\n", false),
+        ];
+        for (test_case, expected_result) in test_cases {
+            let open_ends = open_ends(test_case);
+            let incomplete = match open_ends.quote {
+                Some(err) => err.is_incomplete(test_case),
+                None => {
+                    open_ends.backslash
+                        || open_ends.here_document.is_some()
+                        || crate::parser::parse(test_case)
+                            .is_err_and(|err| err.is_incomplete(test_case))
+                }
+            };
+            assert_eq!(incomplete, expected_result, "{:?}", test_case);
+        }
+    }
+
+    #[test]
+    fn tokenize_should_fail_on_unterminated_substitutions() {
+        let test_cases = vec![
+            ("echo a$(b", ")", 6),
+            ("echo `b", "`", 5),
+        ];
+        for (test_case, closing, start) in test_cases {
+            let err = tokenize(test_case).unwrap_err();
+            assert_eq!(
+                err.message,
+                format!("unexpected EOF while looking for matching `{}'", closing)
+            );
+            assert_eq!(err.span.start, start);
+        }
+    }
+
+    #[test]
+    fn read_substitution_should_stop_at_the_matching_closing_parenthesis() {
+        let test_cases = vec![
+            ("echo a) b", Ok(String::from("echo a"))),
+            ("(a) \\) b) c", Ok(String::from("(a) \\) b"))),
+            (r#""$(echo ")")" x) y"#, Ok(String::from(r#""$(echo ")")" x"#))),
+            ("echo ')' b", Err(String::from("echo ')' b"))),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(read_substitution(&mut test_case.chars().peekable()), expected_result);
+        }
+    }
+}
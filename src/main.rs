@@ -1,33 +1,61 @@
-use core::str;
+use core::str::{self, Chars};
 use std::{
     cell::RefCell,
-    collections::HashSet,
+    collections::{BTreeMap, HashMap, HashSet},
     env,
     fs,
-    io::{self, Write},
-    os::unix::process::CommandExt, // Added import for CommandExt
+    io::{self, BufRead, BufReader, Read, Seek, Write},
+    iter::{self, Peekable},
+    mem,
+    ops::ControlFlow,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    os::unix::{
+        fs::PermissionsExt,
+        process::CommandExt, // Added import for CommandExt
+    },
+    process,
     path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 use rustyline::{
     completion::{Completer, Pair},
     error::ReadlineError,
+    history::DefaultHistory,
     Context, Editor, Helper,
 };
 use rustyline::highlight::Highlighter;
 use rustyline::hint::Hinter;
 use rustyline::validate::Validator;
-use std::process::Output;
 use std::fs::{File, OpenOptions};
 use std::path::Path;
-use std::process::Stdio;
-use std::string::FromUtf8Error;
-use std::env::VarError;
 
-const BUILTIN_COMMANDS: [&str; 2] = ["echo", "exit"];
+mod arithmetic;
+mod jobs;
+mod lexer;
+mod parser;
+mod traps;
+
+use jobs::{Job, JobState, Jobs};
+use lexer::{
+    arithmetic_expression, is_name, read_backquoted, read_braced, read_substitution, SyntaxError,
+};
+use parser::{Redirection, ShellExec};
+use traps::{Condition, Traps};
+
+/// Commands the shell runs itself, which `type` reports and completion offers.
+const BUILTIN_COMMANDS: [&str; 19] = [
+    "echo", "exit", "type", "pwd", "cd", "export", "unset", "shopt", "jobs", "fg", "bg", "wait",
+    "trap", "shift", "set", "let", "break", "continue", "read",
+];
+
+/// Search path used for commands when `PATH` is unset.
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
 
 #[derive(Default)]
 struct ShellCompleter {
     state: RefCell<CompletionState>,
+    /// The `PATH` to complete commands from, kept in sync with the shell's.
+    path: String,
 }
 
 #[derive(Default)] // Added Default derive to CompletionState
@@ -62,16 +90,14 @@ impl Completer for ShellCompleter {
 
         // Collect external executables
         let mut external_commands = Vec::new();
-        if let Ok(path_var) = env::var("PATH") {
-            for dir in path_var.split(':') {
-                if let Ok(entries) = fs::read_dir(dir) {
-                    for entry in entries.flatten() {
-                        if let Ok(file_type) = entry.file_type() {
-                            if file_type.is_file() || file_type.is_symlink() {
-                                if let Some(name) = entry.file_name().to_str() {
-                                    if !BUILTIN_COMMANDS.contains(&name) {
-                                        external_commands.push(name.to_string());
-                                    }
+        for dir in self.path.split(':') {
+            if let Ok(entries) = fs::read_dir(dir) {
+                for entry in entries.flatten() {
+                    if let Ok(file_type) = entry.file_type() {
+                        if file_type.is_file() || file_type.is_symlink() {
+                            if let Some(name) = entry.file_name().to_str() {
+                                if !BUILTIN_COMMANDS.contains(&name) {
+                                    external_commands.push(name.to_string());
                                 }
                             }
                         }
@@ -188,335 +214,1168 @@ impl Hinter for ShellCompleter {
 }
 impl Validator for ShellCompleter {}
 
-#[derive(Debug, PartialEq, Eq)]
+/// What a command's file descriptors are redirected to. Descriptors without an
+/// entry are inherited from the shell, and `None` means closed.
+#[derive(Debug, Default)]
+struct RedirectedFds {
+    fds: BTreeMap<RawFd, Option<OwnedFd>>,
+}
+
+impl RedirectedFds {
+    fn redirect(&mut self, fd: RawFd, target: impl Into<OwnedFd>) -> io::Result<()> {
+        self.insert(fd, Some(target.into().as_raw_fd()))
+    }
 
-enum ShellExec {
+    /// Makes `fd` a copy of `source`, as it is after the redirections so far.
+    fn duplicate(&mut self, fd: RawFd, source: RawFd) -> io::Result<()> {
+        let source = match self.fds.get(&source) {
+            Some(Some(target)) => target.as_raw_fd(),
+            Some(None) => return Err(io::Error::from_raw_os_error(libc::EBADF)),
+            None => source,
+        };
+        self.insert(fd, Some(source))
+    }
+
+    fn close(&mut self, fd: RawFd) -> io::Result<()> {
+        self.insert(fd, None)
+    }
 
-    PrintToStd(Command),
+    /// Redirects `fd` to a copy of `target`, or closes it if `None`. Targets
+    /// are kept above every redirected descriptor, so that applying one
+    /// redirection never replaces the target of another.
+    fn insert(&mut self, fd: RawFd, target: Option<RawFd>) -> io::Result<()> {
+        let above = self.highest_fd().max(fd) + 1;
+        let target = target.map(|target| duplicate_fd_above(target, above)).transpose()?;
+        for other in self.fds.values_mut().flatten() {
+            if other.as_raw_fd() == fd {
+                *other = duplicate_fd_above(other.as_raw_fd(), above)?;
+            }
+        }
+        self.fds.insert(fd, target);
+        Ok(())
+    }
 
-    RedirectedStdOut(Command, PathBuf),
+    /// Writes a line of builtin output to one of the descriptors. Failing to
+    /// write, say because the reader of a pipe is gone, is an error rather
+    /// than a panic.
+    fn write_line(&self, fd: RawFd, line: &str) -> io::Result<()> {
+        let mut output = Vec::with_capacity(line.len() + 1);
+        output.extend_from_slice(line.as_bytes());
+        output.push(b'\n');
+        match self.fds.get(&fd) {
+            None if fd == libc::STDERR_FILENO => io::stderr().write_all(&output),
+            None => io::stdout().write_all(&output),
+            Some(None) => Err(io::Error::from_raw_os_error(libc::EBADF)),
+            Some(Some(target)) => File::from(target.try_clone()?).write_all(&output),
+        }
+    }
 
-    RedirectedStdErr(Command, PathBuf),
+    /// Applies the redirections to the shell itself, for as long as a compound
+    /// command runs. Returns copies of the descriptors they replace, to put
+    /// them back with `restore_fds`.
+    fn apply_to_shell(&self) -> io::Result<Vec<(RawFd, Option<OwnedFd>)>> {
+        // Descriptors that aren't open are closed again afterwards. The copies
+        // go above every descriptor the redirections replace.
+        let above = self.highest_fd() + 1;
+        let saved: Vec<_> =
+            self.fds.keys().map(|&fd| (fd, duplicate_fd_above(fd, above).ok())).collect();
+        if let Err(err) = self.apply() {
+            restore_fds(saved);
+            return Err(err);
+        }
+        Ok(saved)
+    }
 
-    RedirectedStdOutAppend(Command, PathBuf),
+    /// The descriptor to read from in place of `fd`, or `None` if it is closed.
+    fn input(&self, fd: RawFd) -> Option<RawFd> {
+        match self.fds.get(&fd) {
+            None => Some(fd),
+            Some(target) => target.as_ref().map(|target| target.as_raw_fd()),
+        }
+    }
 
-    RedirectedStdErrAppend(Command, PathBuf),
+    /// The highest redirected descriptor, and at least 9.
+    fn highest_fd(&self) -> RawFd {
+        self.fds.keys().copied().max().unwrap_or_default().max(9)
+    }
 
+    /// Applies the redirections to the current process, which is about to run
+    /// a program.
+    fn apply(&self) -> io::Result<()> {
+        for (&fd, target) in &self.fds {
+            match target {
+                Some(target) => {
+                    if unsafe { libc::dup2(target.as_raw_fd(), fd) } == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                // Closing a descriptor that isn't open is not an error.
+                None => unsafe {
+                    libc::close(fd);
+                },
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
-
 enum Command {
-
     Exit(String),
-
     Echo(String),
-
     Type(String),
-
     Pwd,
-
     Cd(String),
-
+    Export(Vec<String>),
+    Unset(Vec<String>),
+    Shopt(Vec<String>),
+    Jobs(Vec<String>),
+    Fg(Vec<String>),
+    Bg(Vec<String>),
+    Wait(Vec<String>),
+    Trap(Vec<String>),
+    Shift(Vec<String>),
+    Set(Vec<String>),
+    Let(Vec<String>),
+    Break(Vec<String>),
+    Continue(Vec<String>),
+    Read(Vec<String>),
     SysProgram(String, Vec<String>),
-
     Empty,
+}
 
-    Invalid,
-
+impl Command {
+    /// Resolves fully expanded arguments into the builtin or program to run.
+    fn from_args(args: Vec<String>) -> Self {
+        if let Some((head, tail)) = args.split_first() {
+            match head.as_str() {
+                "echo" => Command::Echo(tail.join(" ")),
+                "exit" => Command::Exit(tail.join(" ")),
+                "type" => Command::Type(tail.join(" ")),
+                "pwd" => Command::Pwd,
+                "cd" => Command::Cd(tail.join(" ")),
+                "export" => Command::Export(tail.to_vec()),
+                "unset" => Command::Unset(tail.to_vec()),
+                "shopt" => Command::Shopt(tail.to_vec()),
+                "jobs" => Command::Jobs(tail.to_vec()),
+                "fg" => Command::Fg(tail.to_vec()),
+                "bg" => Command::Bg(tail.to_vec()),
+                "wait" => Command::Wait(tail.to_vec()),
+                "trap" => Command::Trap(tail.to_vec()),
+                "shift" => Command::Shift(tail.to_vec()),
+                "set" => Command::Set(tail.to_vec()),
+                "let" => Command::Let(tail.to_vec()),
+                "break" => Command::Break(tail.to_vec()),
+                "continue" => Command::Continue(tail.to_vec()),
+                "read" => Command::Read(tail.to_vec()),
+                c => Command::SysProgram(c.to_owned(), tail.to_vec()),
+            }
+        } else {
+            Command::Empty
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
-
 enum CommandOutput {
-
     StdOut(String),
+    StdErr(String, i32),
+    Exited(i32),
+    Noop,
+}
 
-    StdErr(String),
-
-    Wrapped(String, Output),
+impl CommandOutput {
+    /// The exit status of the command that produced this output.
+    fn status(&self) -> i32 {
+        match self {
+            CommandOutput::StdOut(_) | CommandOutput::Noop => 0,
+            CommandOutput::StdErr(_, status) | CommandOutput::Exited(status) => *status,
+        }
+    }
+}
 
-    Noop,
+/// State that lives for the whole shell session.
+#[derive(Debug, Default)]
+struct Shell {
+    /// Exit status of the most recently executed command, reported by `$?`.
+    last_status: i32,
+    /// Shell variables, seeded from the environment at startup.
+    variables: HashMap<String, String>,
+    /// Names of the variables passed on to the environment of child processes.
+    exported: HashSet<String>,
+    /// Exit status of the last command substitution run while expanding the
+    /// current command, if any.
+    substitution_status: Option<i32>,
+    /// Options set with the `shopt` builtin.
+    options: ShellOptions,
+    /// Jobs started in the background or stopped.
+    jobs: Jobs,
+    /// Process ID of the most recent background job, reported by `$!`.
+    last_background_pid: Option<libc::pid_t>,
+    /// Whether jobs get process groups of their own and take turns at the
+    /// terminal, as they do when the shell is interactive. Forked subshells
+    /// never do job control.
+    job_control: bool,
+    /// Whether the shell reads commands from the user rather than a script.
+    interactive: bool,
+    /// Name of the shell or script, reported by `$0`.
+    name: String,
+    /// Arguments given to the script, reported by `$1`, `$2` and so on, and
+    /// replaced with `set`.
+    positional: Vec<String>,
+    /// Process ID of the shell, reported by `$$`, even in subshells.
+    pid: u32,
+    /// Commands set with the `trap` builtin.
+    traps: Traps,
+    /// How many loops the running command is in, which `break` and `continue`
+    /// can leave.
+    loop_depth: usize,
+    /// Whether the running command tests the condition of an `if`, `while` or
+    /// `until`, where failing doesn't set off the `ERR` trap.
+    in_condition: bool,
+}
 
+/// Shell options that can be turned on and off with `shopt`.
+#[derive(Debug, Default)]
+struct ShellOptions {
+    /// Patterns that match no files expand to nothing instead of themselves.
+    nullglob: bool,
+    /// Patterns that match no files are an error, and the command isn't run.
+    failglob: bool,
 }
 
-fn main() -> Result<()> {
+impl ShellOptions {
+    const NAMES: [&'static str; 2] = ["failglob", "nullglob"];
 
-    let home = env::var("HOME")?;
+    fn get_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "failglob" => Some(&mut self.failglob),
+            "nullglob" => Some(&mut self.nullglob),
+            _ => None,
+        }
+    }
+}
 
-    let path = env::var("PATH")?;
+impl Shell {
+    fn from_env() -> Self {
+        let variables: HashMap<String, String> = env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+        Shell {
+            exported: variables.keys().cloned().collect(),
+            variables,
+            pid: process::id(),
+            ..Default::default()
+        }
+    }
 
-    let mut rl = Editor::new()?;
+    /// The directories searched for commands: the current value of `PATH`, or
+    /// a default search path if it is unset.
+    fn path(&self) -> String {
+        self.parameter("PATH")
+            .unwrap_or_else(|| String::from(DEFAULT_PATH))
+    }
 
-    rl.set_helper(Some(ShellCompleter::default()));
+    /// The environment for child processes: every exported variable that is set.
+    fn environment(&self) -> Vec<(String, String)> {
+        self.exported
+            .iter()
+            .filter_map(|name| Some((name.clone(), self.variables.get(name)?.clone())))
+            .collect()
+    }
 
-    loop {
+    /// Joins values with the first character of `IFS`, as `"$*"` does.
+    fn join_fields(&self, values: &[String]) -> String {
+        let separator = match self.variables.get("IFS") {
+            Some(ifs) => ifs.chars().next().map(String::from).unwrap_or_default(),
+            None => String::from(" "),
+        };
+        values.join(&separator)
+    }
 
-        match rl.readline("$ ") {
+    /// The value of a parameter, or `None` if it is unset.
+    fn parameter(&self, name: &str) -> Option<String> {
+        match name {
+            "?" => Some(self.last_status.to_string()),
+            "!" => self.last_background_pid.map(|pid| pid.to_string()),
+            "0" => Some(self.name.clone()),
+            "#" => Some(self.positional.len().to_string()),
+            "@" => Some(self.positional.join(" ")),
+            "*" => Some(self.join_fields(&self.positional)),
+            "$" => Some(self.pid.to_string()),
+            "-" => {
+                let flags = [(self.interactive, 'i'), (self.job_control, 'm')];
+                Some(flags.iter().filter(|(on, _)| *on).map(|(_, flag)| flag).collect())
+            }
+            _ if name.bytes().all(|b| b.is_ascii_digit()) => {
+                let index = name.parse::<usize>().ok()?.checked_sub(1)?;
+                self.positional.get(index).cloned()
+            }
+            _ => self.variables.get(name).cloned(),
+        }
+    }
+}
 
-            Ok(line) => {
+fn main() -> Result<()> {
+    let mut shell = Shell::from_env();
+    let mut args = env::args();
+    shell.name = args.next().unwrap_or_default();
+    let mut input = match args.next() {
+        // `shell -c command [name args...]`
+        Some(option) if option == "-c" => {
+            let Some(command) = args.next() else {
+                eprintln!("{}: -c: option requires an argument", shell.name);
+                process::exit(2);
+            };
+            if let Some(name) = args.next() {
+                shell.name = name;
+            }
+            shell.positional = args.collect();
+            Input::script(format!("{}: -c", shell.name), io::Cursor::new(command))
+        }
+        // `shell script args...`, which is also how a script starting with a
+        // `#!` line naming the shell gets run.
+        Some(script) => {
+            let file = match File::open(&script) {
+                Ok(file) => file,
+                Err(err) => {
+                    eprintln!("{}: {}", script, describe_io_error(&err));
+                    process::exit(127);
+                }
+            };
+            shell.name = script.clone();
+            shell.positional = args.collect();
+            Input::script(script, BufReader::new(file))
+        }
+        // Commands piped in or redirected from a file. They are read a byte at
+        // a time so that the commands reading stdin get the rest of it.
+        None if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 => {
+            let stdin = File::from(duplicate_fd(libc::STDIN_FILENO)?);
+            Input::script(shell.name.clone(), BufReader::with_capacity(1, stdin))
+        }
+        None => {
+            shell.interactive = true;
+            shell.job_control = jobs::enable_job_control();
+            let mut rl = Editor::new()?;
+            rl.set_helper(Some(ShellCompleter::default()));
+            Input::Interactive(Box::new(rl))
+        }
+    };
+    loop {
+        if let Input::Interactive(rl) = &mut input {
+            if let Some(helper) = rl.helper_mut() {
+                helper.path = shell.path();
+            }
+            for notice in shell.jobs.notify() {
+                eprintln!("{}", notice);
+            }
+        }
+        if let Err(Error::Exit(status)) = run_pending_traps(&mut shell) {
+            shell.last_status = status;
+            break;
+        }
+        let exec = match read_command(&mut input, &mut shell) {
+            Ok(Some(exec)) => exec,
+            Ok(None) => continue,
+            Err(ReadlineError::Interrupted) => {
+                let interrupt = Condition::Signal(libc::SIGINT);
+                if let Err(Error::Exit(status)) = run_trap(interrupt, &mut shell) {
+                    shell.last_status = status;
+                    break;
+                }
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                break;
+            }
+        };
+        match run_complete_command(exec, &mut shell) {
+            Ok(status) => shell.last_status = status,
+            Err(Error::Exit(status)) => {
+                shell.last_status = status;
+                break;
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                shell.last_status = 1;
+            }
+        }
+    }
+    if let Err(Error::Exit(status)) = run_trap(Condition::Exit, &mut shell) {
+        shell.last_status = status;
+    }
+    process::exit(shell.last_status)
+}
 
-                let trimmed_input = line.trim();
+/// Where the shell reads commands from.
+enum Input {
+    /// The terminal, through the line editor, with prompts, completion and
+    /// history.
+    Interactive(Box<Editor<ShellCompleter, DefaultHistory>>),
+    /// A script, from a file, the `-c` option or stdin, line by line.
+    Script {
+        lines: io::Lines<Box<dyn BufRead>>,
+        /// What its syntax errors are reported with, such as its file name.
+        name: String,
+        /// The number of lines read so far.
+        line: usize,
+    },
+}
 
-                if !trimmed_input.is_empty() {  // Removed redundant parentheses
+impl Input {
+    fn script(name: String, reader: impl BufRead + 'static) -> Input {
+        let reader: Box<dyn BufRead> = Box::new(reader);
+        Input::Script {
+            lines: reader.lines(),
+            name,
+            line: 0,
+        }
+    }
 
-                    rl.add_history_entry(trimmed_input);
+    /// Reads the next line, without its line terminator. The prompt is only
+    /// shown interactively.
+    fn read_line(&mut self, prompt: &str) -> rustyline::Result<String> {
+        match self {
+            Input::Interactive(rl) => rl.readline(prompt),
+            Input::Script { lines, line, .. } => match lines.next() {
+                Some(next) => {
+                    *line += 1;
+                    Ok(next?)
+                }
+                None => Err(ReadlineError::Eof),
+            },
+        }
+    }
+}
 
+/// Reads and parses the next command, reading more lines while the command or
+/// the body of one of its here-documents is incomplete. Returns `None` for a
+/// blank line or a syntax error, which is reported with status 2. A script
+/// stops at its first syntax error, as if it ended there.
+fn read_command(input: &mut Input, shell: &mut Shell) -> rustyline::Result<Option<ShellExec>> {
+    let mut source = input.read_line("$ ")? + "\n";
+    let first_line = match input {
+        Input::Interactive(_) => 1,
+        Input::Script { line, .. } => *line,
+    };
+    let parsed = loop {
+        let open_ends = lexer::open_ends(&source);
+        if open_ends.backslash || open_ends.here_document.is_some() {
+            match input.read_line("> ") {
+                Ok(line) => {
+                    source.push_str(&line);
+                    source.push('\n');
+                    continue;
+                }
+                // Ending the input early ends the body of a here-document too.
+                Err(ReadlineError::Eof) => {
+                    if let Some(delimiter) = open_ends.here_document {
+                        eprintln!(
+                            "warning: here-document delimited by end-of-file (wanted `{}')",
+                            delimiter
+                        );
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        let parsed = match open_ends.quote {
+            Some(err) => Err(err),
+            None => parser::parse(&source),
+        };
+        match parsed {
+            Err(err) if err.is_incomplete(&source) => match input.read_line("> ") {
+                Ok(line) => {
+                    source.push_str(&line);
+                    source.push('\n');
+                }
+                // Out of tokens for the rest of the command, rather than at a
+                // particular one.
+                Err(ReadlineError::Eof) if err.span.start == source.len() => {
+                    break Err(SyntaxError {
+                        message: String::from("syntax error: unexpected end of file"),
+                        span: err.span,
+                    })
+                }
+                Err(ReadlineError::Eof) => break Err(err),
+                Err(err) => return Err(err),
+            },
+            parsed => break parsed,
+        }
+    };
+    if let Input::Interactive(rl) = input {
+        if !source.trim().is_empty() {
+            let _ = rl.add_history_entry(source.trim());
+        }
+    }
+    let exec = match parsed {
+        Ok(exec) => exec,
+        Err(err) => {
+            shell.last_status = 2;
+            return match input {
+                Input::Interactive(_) => {
+                    eprintln!("{}", err.diagnostic(&source, first_line));
+                    Ok(None)
+                }
+                Input::Script { name, .. } => {
+                    eprintln!("{}: {}", name, err.diagnostic(&source, first_line));
+                    Err(ReadlineError::Eof)
                 }
+            };
+        }
+    };
+    if exec == ShellExec::PrintToStd(vec![]) {
+        return Ok(None);
+    }
+    Ok(Some(exec))
+}
 
-                let exec = parse(trimmed_input);
+/// Runs a parsed command line and returns its exit status.
+fn run_shell_exec(exec: ShellExec, shell: &mut Shell) -> Result<i32> {
+    match exec {
+        ShellExec::PrintToStd(words) => command_status(run_simple_command(
+            &words,
+            RedirectedFds::default(),
+            shell,
+            false,
+        )),
+        ShellExec::Redirected(words, redirections) => {
+            command_status(run_redirected_command(&words, redirections, shell, false))
+        }
+        ShellExec::Pipeline(stages) => command_status(exec_pipeline(stages, shell)),
+        ShellExec::And(left, right) => {
+            let status = run_shell_exec(*left, shell)?;
+            shell.last_status = status;
+            if status != 0 {
+                return Ok(status);
+            }
+            run_shell_exec(*right, shell)
+        }
+        ShellExec::Or(left, right) => {
+            let status = run_shell_exec(*left, shell)?;
+            shell.last_status = status;
+            if status == 0 {
+                return Ok(status);
+            }
+            run_shell_exec(*right, shell)
+        }
+        ShellExec::List(commands) => {
+            for command in commands {
+                shell.last_status = run_complete_command(command, shell)?;
+            }
+            Ok(shell.last_status)
+        }
+        ShellExec::Background(command, text) => {
+            command_status(run_in_background(*command, text, shell))
+        }
+        ShellExec::Arithmetic(expression) => {
+            command_status(run_arithmetic_command(&expression, shell))
+        }
+        ShellExec::If(branches, otherwise) => {
+            for (condition, body) in branches {
+                if run_condition(condition, shell)? == 0 {
+                    return run_complete_command(body, shell);
+                }
+            }
+            match otherwise {
+                Some(body) => run_complete_command(*body, shell),
+                None => Ok(0),
+            }
+        }
+        ShellExec::While(condition, body) => run_loop(*condition, *body, false, shell),
+        ShellExec::Until(condition, body) => run_loop(*condition, *body, true, shell),
+        ShellExec::For(name, words, body) => command_status(run_for(name, words, *body, shell)),
+        ShellExec::RedirectedCompound(command, redirections) => {
+            command_status(run_redirected_compound(*command, redirections, shell))
+        }
+    }
+}
 
-                match exec {
+/// Runs the condition of an `if`, `while` or `until`.
+fn run_condition(condition: ShellExec, shell: &mut Shell) -> Result<i32> {
+    let in_condition = mem::replace(&mut shell.in_condition, true);
+    let status = run_complete_command(condition, shell);
+    shell.in_condition = in_condition;
+    status
+}
 
-                    ShellExec::PrintToStd(Command::Exit(s)) if s == "0" => break,
+/// Runs a `while` loop, or an `until` loop, and returns the status of the last
+/// command of its body, or 0 if the body never ran.
+fn run_loop(condition: ShellExec, body: ShellExec, until: bool, shell: &mut Shell) -> Result<i32> {
+    in_loop(shell, |shell| {
+        let mut status = 0;
+        loop {
+            let tested = match body_flow(run_condition(condition.clone(), shell))? {
+                ControlFlow::Continue(tested) if interrupted(tested, shell) => return Ok(tested),
+                ControlFlow::Continue(tested) => tested,
+                ControlFlow::Break(status) => return Ok(status),
+            };
+            if (tested == 0) == until {
+                return Ok(status);
+            }
+            match body_flow(run_complete_command(body.clone(), shell))? {
+                ControlFlow::Continue(ran) if interrupted(ran, shell) => return Ok(ran),
+                ControlFlow::Continue(ran) => status = ran,
+                ControlFlow::Break(ran) => return Ok(ran),
+            }
+        }
+    })
+}
 
-                    ShellExec::RedirectedStdOut(Command::Exit(s), _) if s == "0" => break,
+/// Runs a `for` loop and returns the status of the last command of its body,
+/// or 0 if there was nothing to loop over.
+fn run_for(
+    name: String,
+    words: Option<Vec<String>>,
+    body: ShellExec,
+    shell: &mut Shell,
+) -> Result<i32> {
+    let values = match words {
+        Some(words) => {
+            let mut values = Vec::new();
+            for word in words {
+                values.extend(expand_word(&word, shell)?);
+            }
+            values
+        }
+        None => shell.positional.clone(),
+    };
+    in_loop(shell, |shell| {
+        let mut status = 0;
+        for value in values {
+            shell.variables.insert(name.clone(), value);
+            match body_flow(run_complete_command(body.clone(), shell))? {
+                ControlFlow::Continue(ran) if interrupted(ran, shell) => return Ok(ran),
+                ControlFlow::Continue(ran) => status = ran,
+                ControlFlow::Break(ran) => return Ok(ran),
+            }
+        }
+        Ok(status)
+    })
+}
 
-                    ShellExec::PrintToStd(Command::Empty) => continue,
+/// Whether a command in a loop was interrupted with Ctrl-C. With job control
+/// the signal only reaches the foreground job, but it stops the loops around
+/// it as well, the way it would stop the shell otherwise.
+fn interrupted(status: i32, shell: &Shell) -> bool {
+    shell.job_control && status == 128 + libc::SIGINT
+}
 
-                    ShellExec::RedirectedStdOut(Command::Empty, _) => continue,
+/// Runs a loop, counting it in for `break` and `continue`.
+fn in_loop(shell: &mut Shell, run: impl FnOnce(&mut Shell) -> Result<i32>) -> Result<i32> {
+    shell.loop_depth += 1;
+    let status = run(shell);
+    shell.loop_depth -= 1;
+    status
+}
 
-                    ShellExec::PrintToStd(c) => {
+/// Catches `break` and `continue` coming out of a loop's body or condition:
+/// tells whether the loop goes on, with the status so far, and passes on what
+/// is meant for the loops around it.
+fn body_flow(result: Result<i32>) -> Result<ControlFlow<i32, i32>> {
+    match result {
+        Err(Error::Break(1)) => Ok(ControlFlow::Break(0)),
+        Err(Error::Break(count)) => Err(Error::Break(count - 1)),
+        Err(Error::Continue(1)) => Ok(ControlFlow::Continue(0)),
+        Err(Error::Continue(count)) => Err(Error::Continue(count - 1)),
+        result => result.map(ControlFlow::Continue),
+    }
+}
 
-                        let output = exec_command(c, &path, &home)?;
+/// Runs a compound command with its redirections applied to the shell itself
+/// meanwhile, so that every command inside it sees them.
+fn run_redirected_compound(
+    command: ShellExec,
+    redirections: Vec<Redirection>,
+    shell: &mut Shell,
+) -> Result<i32> {
+    let Some(targets) = open_redirections(redirections, shell)? else {
+        return Ok(1);
+    };
+    let _ = io::stdout().flush();
+    let saved = targets.apply_to_shell()?;
+    let status = run_shell_exec(command, shell);
+    let _ = io::stdout().flush();
+    restore_fds(saved);
+    status
+}
 
-                        match output {
+/// Runs `((expression))`, whose expression is expanded as if double-quoted.
+fn run_arithmetic_command(expression: &str, shell: &mut Shell) -> Result<i32> {
+    let expression = expand_string(expression, shell)?;
+    match evaluate_arithmetic(&expression, shell) {
+        Ok(value) => Ok(if value != 0 { 0 } else { 1 }),
+        Err(message) => Err(Error::Expansion(format!("((: {}", message))),
+    }
+}
 
-                            CommandOutput::StdOut(s) => println!("{}", s),
+/// Runs a complete command, a list or an and-or list, followed by the traps it
+/// sets off: `ERR` if it fails and those of the signals that arrived meanwhile.
+fn run_complete_command(exec: ShellExec, shell: &mut Shell) -> Result<i32> {
+    let (status, last_ran) = match exec {
+        ShellExec::List(_) => return run_shell_exec(exec, shell),
+        // Only a failure of the last command of an and-or list sets off `ERR`,
+        // and it doesn't run when a command in front of `&&` fails.
+        ShellExec::And(left, right) => match run_shell_exec(*left, shell)? {
+            0 => {
+                shell.last_status = 0;
+                (run_shell_exec(*right, shell)?, true)
+            }
+            status => (status, false),
+        },
+        exec => {
+            let compound = exec.is_compound();
+            (run_shell_exec(exec, shell)?, !compound)
+        }
+    };
+    shell.last_status = status;
+    if status != 0 && last_ran && !shell.in_condition {
+        run_trap(Condition::Err, shell)?;
+    }
+    run_pending_traps(shell)?;
+    Ok(status)
+}
 
-                            CommandOutput::StdErr(s) => eprintln!("{}", s),
+/// Runs the trap set for a condition, if any, leaving `$?` as it was. Traps
+/// don't set off other traps.
+fn run_trap(condition: Condition, shell: &mut Shell) -> Result<()> {
+    let command = match shell.traps.get(condition) {
+        Some(command) if !command.is_empty() && !shell.traps.running => command.to_string(),
+        _ => return Ok(()),
+    };
+    let status = shell.last_status;
+    shell.traps.running = true;
+    let result = run_script(&command, shell);
+    shell.traps.running = false;
+    result?;
+    shell.last_status = status;
+    Ok(())
+}
 
-                            CommandOutput::Wrapped(c, output) => {
+/// Runs the traps of the signals that arrived since they last ran.
+fn run_pending_traps(shell: &mut Shell) -> Result<()> {
+    if shell.traps.running {
+        return Ok(());
+    }
+    for signal in traps::take_pending() {
+        run_trap(Condition::Signal(signal), shell)?;
+    }
+    Ok(())
+}
 
-                                if !output.stdout.is_empty() {
+/// Starts a command in a forked child without waiting for it, and adds it to
+/// the job table.
+fn run_in_background(exec: ShellExec, text: String, shell: &mut Shell) -> Result<i32> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error().into()),
+        0 => {
+            if shell.job_control {
+                let _ = jobs::enter_job(0, false);
+                shell.job_control = false;
+            }
+            shell.traps.reset_for_subshell();
+            unsafe { libc::signal(libc::SIGPIPE, libc::SIG_DFL) };
+            let status = subshell_status(run_shell_exec(exec, shell));
+            let _ = io::stdout().flush();
+            unsafe { libc::_exit(status) }
+        }
+        pid => {
+            let pgid = shell.job_control.then(|| {
+                // The child does this too, whichever runs first wins.
+                unsafe { libc::setpgid(pid, pid) };
+                pid
+            });
+            let id = shell.jobs.add(Job::new(vec![pid], pgid, text));
+            shell.last_background_pid = Some(pid);
+            if shell.interactive {
+                eprintln!("[{}] {}", id, pid);
+            }
+            Ok(0)
+        }
+    }
+}
 
-                                    println!("{}", String::from_utf8(output.stdout)?.trim())
+/// The status a forked subshell exits with after running a command.
+fn subshell_status(result: Result<i32>) -> i32 {
+    match result {
+        Ok(status) | Err(Error::Exit(status)) => status,
+        // Leaving loops in a subshell leaves the subshell.
+        Err(Error::Break(_) | Error::Continue(_)) => 0,
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
 
-                                }
+/// Turns an error from running a single command into a diagnostic and a
+/// failure status, so that the rest of the command line and the session carry
+/// on. Only `exit`, `break` and `continue` keep unwinding.
+fn command_status(result: Result<i32>) -> Result<i32> {
+    match result {
+        Err(err @ (Error::Exit(_) | Error::Break(_) | Error::Continue(_))) => Err(err),
+        Err(err) => {
+            eprintln!("{}", err);
+            Ok(1)
+        }
+        result => result,
+    }
+}
 
-                                if !output.stderr.is_empty() {
+/// Performs a command's redirections from left to right, then runs it. If a
+/// redirection fails, the command doesn't run and the status is 1.
+fn run_redirected_command(
+    words: &[String],
+    redirections: Vec<Redirection>,
+    shell: &mut Shell,
+    replace_process: bool,
+) -> Result<i32> {
+    match open_redirections(redirections, shell)? {
+        Some(targets) => run_simple_command(words, targets, shell, replace_process),
+        None => Ok(1),
+    }
+}
 
-                                    print_sys_program_failure_to_stderr(c, output.stderr)?
+/// Opens the targets of redirections from left to right. If one fails, reports
+/// it and returns `None`.
+fn open_redirections(
+    redirections: Vec<Redirection>,
+    shell: &mut Shell,
+) -> Result<Option<RedirectedFds>> {
+    let mut targets = RedirectedFds::default();
+    for redirection in redirections {
+        let (result, name) = match redirection {
+            Redirection::Input(fd, file) => {
+                let file = expand_string(&file, shell)?;
+                (File::open(&file).and_then(|f| targets.redirect(fd, f)), file)
+            }
+            Redirection::Output(fd, file) => {
+                let file = expand_string(&file, shell)?;
+                (File::create(&file).and_then(|f| targets.redirect(fd, f)), file)
+            }
+            Redirection::Append(fd, file) => {
+                let file = expand_string(&file, shell)?;
+                let opened = OpenOptions::new().append(true).create(true).open(&file);
+                (opened.and_then(|f| targets.redirect(fd, f)), file)
+            }
+            Redirection::ReadWrite(fd, file) => {
+                let file = expand_string(&file, shell)?;
+                let opened = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&file);
+                (opened.and_then(|f| targets.redirect(fd, f)), file)
+            }
+            Redirection::Duplicate(fd, source) => {
+                (targets.duplicate(fd, source), source.to_string())
+            }
+            Redirection::Close(fd) => (targets.close(fd), fd.to_string()),
+            Redirection::HereDocument(fd, document) => {
+                let text = if document.expand {
+                    expand_here_document(&document.body, shell)?
+                } else {
+                    document.body
+                };
+                let file = here_document_file(&text)?;
+                (targets.redirect(fd, file), fd.to_string())
+            }
+            Redirection::HereString(fd, word) => {
+                let text = expand_string(&word, shell)? + "\n";
+                let file = here_document_file(&text)?;
+                (targets.redirect(fd, file), fd.to_string())
+            }
+        };
+        if let Err(err) = result {
+            eprintln!("{}: {}", name, describe_io_error(&err));
+            return Ok(None);
+        }
+    }
+    Ok(Some(targets))
+}
 
-                                }
+/// Runs a simple command: either a list of variable assignments or a builtin or
+/// program with its arguments. With `replace_process` set, as it is inside a
+/// pipeline stage, an external program replaces the current process.
+fn run_simple_command(
+    words: &[String],
+    targets: RedirectedFds,
+    shell: &mut Shell,
+    replace_process: bool,
+) -> Result<i32> {
+    let split_point = words
+        .iter()
+        .position(|word| assignment(word).is_none())
+        .unwrap_or(words.len());
+    let (assignments, words) = words.split_at(split_point);
+    shell.substitution_status = None;
+    if words.is_empty() {
+        // Each assignment is made before the next one is expanded, so that it
+        // can use the value.
+        for (name, value) in assignments.iter().filter_map(|word| assignment(word)) {
+            let value = expand_string(value, shell)?;
+            shell.variables.insert(name.to_string(), value);
+        }
+        // Assignments alone succeed, unless they ran a command that failed.
+        return Ok(shell.substitution_status.unwrap_or(0));
+    }
+    let assignments = assignments
+        .iter()
+        .filter_map(|word| assignment(word))
+        .map(|(name, value)| Ok((name.to_string(), expand_string(value, shell)?)))
+        .collect::<Result<Vec<(String, String)>>>()?;
+    // Assignments in front of a command only end up in that command's environment,
+    // but a `PATH` given this way is also used to look the command up.
+    let path = assignments
+        .iter()
+        .find(|(name, _)| name == "PATH")
+        .map_or_else(|| shell.path(), |(_, value)| value.clone());
+    let mut environment = shell.environment();
+    environment.extend(assignments);
+    let mut args = Vec::new();
+    for word in words {
+        args.extend(expand_word(word, shell)?);
+    }
+    let command = match Command::from_args(args) {
+        Command::SysProgram(c, args) if replace_process => {
+            return match find_executable_on_path(&path, &c)? {
+                Some(program) => {
+                    let err = match executable_command(&c, &program, &args, &targets, &environment)
+                    {
+                        Ok(mut command) => command.exec(),
+                        Err(err) => err,
+                    };
+                    eprintln!("{}: {}", c, describe_io_error(&err));
+                    Ok(126)
+                }
+                None => {
+                    eprintln!("{}: command not found", c);
+                    Ok(127)
+                }
+            };
+        }
+        command => command,
+    };
+    let output = exec_command(command, &path, &targets, &environment, shell)?;
+    let status = output.status();
+    if let Err(err) = write_output(output, &targets) {
+        eprintln!("write error: {}", describe_io_error(&err));
+        return Ok(1);
+    }
+    Ok(status)
+}
 
-                            }
+/// Writes the output of a builtin either to the shell's own streams or to
+/// wherever they are redirected.
+fn write_output(output: CommandOutput, targets: &RedirectedFds) -> io::Result<()> {
+    match output {
+        CommandOutput::StdOut(s) => targets.write_line(libc::STDOUT_FILENO, &s),
+        CommandOutput::StdErr(s, _) => targets.write_line(libc::STDERR_FILENO, &s),
+        CommandOutput::Exited(_) | CommandOutput::Noop => Ok(()),
+    }
+}
 
-                            CommandOutput::Noop => continue,
+/// Runs every stage of a pipeline concurrently in its own forked process, with
+/// the stdout of each stage connected to the stdin of the next one through an
+/// OS pipe. Returns the exit status of the last stage. With job control, the
+/// stages share a process group of their own and run as a foreground job.
+fn exec_pipeline(stages: Vec<ShellExec>, shell: &mut Shell) -> Result<i32> {
+    let text = stages.iter().map(ShellExec::text).collect::<Vec<_>>().join(" | ");
+    let last = stages.len().saturating_sub(1);
+    let mut children = Vec::with_capacity(stages.len());
+    let mut pgid = None;
+    let mut stdin: Option<OwnedFd> = None;
+    for (i, stage) in stages.into_iter().enumerate() {
+        let (next_stdin, stdout) = if i < last {
+            let (read_end, write_end) = pipe()?;
+            (Some(read_end), Some(write_end))
+        } else {
+            (None, None)
+        };
+        match unsafe { libc::fork() } {
+            -1 => return Err(io::Error::last_os_error().into()),
+            0 => {
+                if shell.job_control {
+                    let _ = jobs::enter_job(pgid.unwrap_or(0), true);
+                    shell.job_control = false;
+                }
+                shell.traps.reset_for_subshell();
+                drop(next_stdin);
+                if let Some(fd) = stdin {
+                    redirect_fd(fd, libc::STDIN_FILENO);
+                }
+                if let Some(fd) = stdout {
+                    redirect_fd(fd, libc::STDOUT_FILENO);
+                }
+                // A builtin writing into a pipe whose reader has gone away
+                // should die quietly, the same way an external program does.
+                unsafe { libc::signal(libc::SIGPIPE, libc::SIG_DFL) };
+                let status = run_pipeline_stage(stage, shell);
+                let _ = io::stdout().flush();
+                unsafe { libc::_exit(status) }
+            }
+            pid => {
+                if shell.job_control {
+                    let leader = *pgid.get_or_insert(pid);
+                    unsafe { libc::setpgid(pid, leader) };
+                }
+                children.push(pid);
+            }
+        }
+        stdin = next_stdin;
+    }
+    Ok(run_in_foreground(Job::new(children, pgid, text), shell)?)
+}
 
-                        }
+/// Waits for a job in the foreground until it finishes or stops, and returns
+/// its status. A job that stops goes into the job table.
+fn run_in_foreground(mut job: Job, shell: &mut Shell) -> io::Result<i32> {
+    wait_for_job(&mut job, shell.job_control)?;
+    match job.state {
+        JobState::Done(status) => Ok(status),
+        state => {
+            let id = shell.jobs.add(job);
+            Ok(finish_foreground_job(id, state, shell))
+        }
+    }
+}
 
-                    }
+/// Waits for a job until it finishes or stops. With job control, the job holds
+/// the terminal meanwhile.
+fn wait_for_job(job: &mut Job, job_control: bool) -> io::Result<()> {
+    if let (true, Some(pgid)) = (job_control, job.pgid) {
+        jobs::give_terminal_to(pgid);
+    }
+    let result = job.wait(true);
+    if job_control {
+        jobs::reclaim_terminal();
+    }
+    result
+}
 
-                    ShellExec::RedirectedStdOut(command, file) => {
+/// Executes a single pipeline stage inside its forked child. External programs
+/// replace the child process so their output streams straight into the pipe.
+fn run_pipeline_stage(stage: ShellExec, shell: &mut Shell) -> i32 {
+    let result = match stage {
+        ShellExec::PrintToStd(words) => {
+            run_simple_command(&words, RedirectedFds::default(), shell, true)
+        }
+        ShellExec::Redirected(words, redirections) => {
+            run_redirected_command(&words, redirections, shell, true)
+        }
+        stage => run_shell_exec(stage, shell),
+    };
+    subshell_status(result)
+}
 
-                        let file = File::create(file)?;
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let (read_end, write_end) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    for fd in [&read_end, &write_end] {
+        // Keep pipe ends from leaking into programs spawned by other stages.
+        if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((read_end, write_end))
+}
 
-                        let output = exec_command(command, &path, &home)?;
+fn redirect_fd(fd: OwnedFd, target: RawFd) {
+    unsafe { libc::dup2(fd.as_raw_fd(), target) };
+}
 
-                        handle_redirected_std_out(file, output)?
+/// Puts back the descriptors that `RedirectedFds::apply_to_shell` replaced.
+fn restore_fds(saved: Vec<(RawFd, Option<OwnedFd>)>) {
+    for (fd, saved) in saved {
+        match saved {
+            Some(saved) => redirect_fd(saved, fd),
+            None => unsafe {
+                libc::close(fd);
+            },
+        }
+    }
+}
 
-                    }
+/// Duplicates a descriptor onto a number of at least 10, out of the way of the
+/// descriptors that redirections usually apply to.
+fn duplicate_fd(fd: RawFd) -> io::Result<OwnedFd> {
+    duplicate_fd_above(fd, 10)
+}
 
-                    ShellExec::RedirectedStdOutAppend(command, file) => {
+/// Duplicates a descriptor onto a number of at least `lowest`.
+fn duplicate_fd_above(fd: RawFd, lowest: RawFd) -> io::Result<OwnedFd> {
+    match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, lowest) } {
+        -1 => Err(io::Error::last_os_error()),
+        duplicate => Ok(unsafe { OwnedFd::from_raw_fd(duplicate) }),
+    }
+}
 
-                        let file = OpenOptions::new().append(true).create(true).open(file)?;
+/// Waits for a child process and converts its wait status into a shell exit
+/// status, using 128 + N for children killed by signal N.
+fn wait_for(pid: libc::pid_t) -> io::Result<i32> {
+    let mut status = 0;
+    while unsafe { libc::waitpid(pid, &mut status, 0) } == -1 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    if libc::WIFSIGNALED(status) {
+        Ok(128 + libc::WTERMSIG(status))
+    } else {
+        Ok(libc::WEXITSTATUS(status))
+    }
+}
 
-                        let output = exec_command(command, &path, &home)?;
+fn exec_command(
+    command: Command,
+    path: &str,
+    targets: &RedirectedFds,
+    environment: &[(String, String)],
+    shell: &mut Shell,
+) -> Result<CommandOutput> {
 
-                        handle_redirected_std_out(file, output)?
+    match command {
 
+        Command::Exit(s) => {
+            let args: Vec<&str> = s.split_whitespace().collect();
+            match args.as_slice() {
+                [] => Err(Error::Exit(shell.last_status)),
+                [code, rest @ ..] => match code.parse::<i64>() {
+                    Ok(_) if !rest.is_empty() => Ok(CommandOutput::StdErr(
+                        String::from("exit: too many arguments"),
+                        1,
+                    )),
+                    Ok(code) => Err(Error::Exit((code & 0xff) as i32)),
+                    Err(_) => {
+                        // The shell exits even if the message can't be written.
+                        let message = format!("exit: {}: numeric argument required", code);
+                        let _ = targets.write_line(libc::STDERR_FILENO, &message);
+                        Err(Error::Exit(2))
                     }
+                },
+            }
+        }
 
-                    ShellExec::RedirectedStdErr(command, file) => {
+        Command::Echo(s) => Ok(CommandOutput::StdOut(s.to_string())),
 
-                        let file = File::create(file)?;
+        Command::Type(c) if BUILTIN_COMMANDS.contains(&c.as_str()) => {
 
-                        let output = exec_command(command, &path, &home)?;
+            Ok(CommandOutput::StdOut(format!("{} is a shell builtin", c)))
 
-                        handle_redirected_std_err(file, output)?
+        }
 
-                    }
+        Command::Type(c) => {
 
-                    ShellExec::RedirectedStdErrAppend(command, file) => {
+            if !c.is_empty() { // Removed redundant parentheses
 
-                        let file = OpenOptions::new().append(true).create(true).open(file)?;
-
-                        let output = exec_command(command, &path, &home)?;
-
-                        handle_redirected_std_err(file, output)?
-
-                    }
-
-                }
-
-            }
-
-            Err(ReadlineError::Interrupted) => continue,
-
-            Err(ReadlineError::Eof) => break,
-
-            Err(err) => {
-
-                eprintln!("Error: {}", err);
-
-                break;
-
-            }
-
-        }
-
-    }
-
-    Ok(())
-
-}
-
-fn handle_redirected_std_out(mut file: File, output: CommandOutput) -> Result<()> {
-
-    match output {
-
-        CommandOutput::StdOut(s) => {
-
-            writeln!(file, "{}", s)?;
-
-            file.flush()?
-
-        }
-
-        CommandOutput::StdErr(s) => eprintln!("{}", s),
-
-        CommandOutput::Wrapped(c, output) => {
-
-            if !output.stdout.is_empty() { // Removed redundant parentheses
-
-                writeln!(file, "{}", String::from_utf8(output.stdout)?.trim())?;
-
-                file.flush()?
-
-            }
-
-            if !output.stderr.is_empty() { // Removed redundant parentheses
-
-                print_sys_program_failure_to_stderr(c, output.stderr)?
-
-            }
-
-        }
-
-        CommandOutput::Noop => (),
-
-    }
-
-    Ok(())
-
-}
-
-fn handle_redirected_std_err(mut file: File, output: CommandOutput) -> Result<()> {
-
-    match output {
-
-        CommandOutput::StdOut(s) => eprintln!("{}", s),
-
-        CommandOutput::StdErr(s) => writeln!(file, "{}", s)?,
-
-        CommandOutput::Wrapped(c, output) => {
-
-            if !output.stdout.is_empty() {
-
-                println!("{}", String::from_utf8(output.stdout)?.trim())
-
-            }
-
-            if !output.stderr.is_empty() {
-
-                let raw_error_message = String::from_utf8(output.stderr)?;
-
-                if let Some(split_point) = raw_error_message.find(&c) {
-
-                    if let Some((_, right_half)) = raw_error_message.split_at_checked(split_point) {
-
-                        let err_msg = &right_half[c.len()..];
-
-                        writeln!(file, "{}{}", c, err_msg.trim())?;
-
-                    } else {
-
-                        writeln!(file, "{}", raw_error_message.trim())?;
-
-                    }
-
-                } else {
-
-                    writeln!(file, "{}", raw_error_message.trim())?;
-
-                }
-
-                file.flush()?;
-
-            }
-
-        }
-
-        CommandOutput::Noop => (),
-
-    }
-
-    Ok(())
-
-}
-
-fn print_sys_program_failure_to_stderr(program: String, stderr: Vec<u8>) -> Result<()> {
-
-    let raw_error_message = String::from_utf8(stderr)?;
-
-    if let Some(split_point) = raw_error_message.find(&program) {
-
-        if let Some((_, right_half)) = raw_error_message.split_at_checked(split_point) {
-
-            let err_msg = &right_half[program.len()..];
-
-            eprintln!("{}{}", program, err_msg.trim());
-
-        } else {
-
-            eprintln!("{}", raw_error_message.trim());
-
-        }
-
-    } else {
-
-        eprintln!("{}", raw_error_message.trim());
-
-    }
-
-    Ok(())
-
-}
-
-fn exec_command(command: Command, path: &str, home: &str) -> Result<CommandOutput> {
-
-    let built_in_commands = ["echo", "exit", "type", "pwd", "cd"];
-
-    match command {
-
-        Command::Exit(s) if s == "0" => Ok(CommandOutput::Noop),
-
-        Command::Exit(s) => Ok(CommandOutput::StdErr(format!("Unknown exit code {}", s))),
-
-        Command::Echo(s) => Ok(CommandOutput::StdOut(s.to_string())),
-
-        Command::Type(c) if built_in_commands.contains(&c.as_str()) => {
-
-            Ok(CommandOutput::StdOut(format!("{} is a shell builtin", c)))
-
-        }
-
-        Command::Type(c) => {
-
-            if !c.is_empty() { // Removed redundant parentheses
-
-                if let Some(executable) = find_executable_on_path(path, &c)? {
+                if let Some(executable) = find_executable_on_path(path, &c)? {
 
                     Ok(CommandOutput::StdOut(format!(
 
@@ -530,7 +1389,7 @@ fn exec_command(command: Command, path: &str, home: &str) -> Result<CommandOutpu
 
                 } else {
 
-                    Ok(CommandOutput::StdErr(format!("{}: not found", c)))
+                    Ok(CommandOutput::StdErr(format!("{}: not found", c), 1))
 
                 }
 
@@ -556,23 +1415,28 @@ fn exec_command(command: Command, path: &str, home: &str) -> Result<CommandOutpu
 
                 let dir_path = if directory == "~" { // Removed redundant parentheses
 
-                    Path::new(home)
+                    match shell.parameter("HOME") {
+                        Some(home) => PathBuf::from(home),
+                        None => {
+                            return Ok(CommandOutput::StdErr(String::from("cd: HOME not set"), 1))
+                        }
+                    }
 
                 } else {
 
-                    Path::new(&directory)
+                    PathBuf::from(&directory)
 
                 };
 
                 if env::set_current_dir(dir_path).is_err() {
 
-                    Ok(CommandOutput::StdOut(format!(
+                    Ok(CommandOutput::StdErr(
 
-                        "cd: {}: No such file or directory",
+                        format!("cd: {}: No such file or directory", directory),
 
-                        directory
+                        1,
 
-                    )))
+                    ))
 
                 } else {
 
@@ -592,353 +1456,1661 @@ fn exec_command(command: Command, path: &str, home: &str) -> Result<CommandOutpu
 
             if let Some(program) = find_executable_on_path(path, &c)? {
 
-                let output = run_executable_with_args(&program, args.as_slice())?;
-
-                Ok(CommandOutput::Wrapped(c.to_string(), output))
+                let text = iter::once(&c).chain(&args).cloned().collect::<Vec<_>>().join(" ");
+                let command = executable_command(&c, &program, &args, targets, environment);
+                match command.and_then(|command| run_executable(command, text, shell)) {
+                    Ok(status) => Ok(CommandOutput::Exited(status)),
+                    // Found but not runnable, say for lack of permission or
+                    // because it is a directory.
+                    Err(err) => Ok(CommandOutput::StdErr(
+                        format!("{}: {}", c, describe_io_error(&err)),
+                        126,
+                    )),
+                }
 
             } else {
 
-                Ok(CommandOutput::StdErr(format!("{}: command not found", c)))
+                Ok(CommandOutput::StdErr(format!("{}: command not found", c), 127))
 
             }
 
         }
 
-        Command::Empty => Ok(CommandOutput::Noop),
-
-        Command::Invalid => Err(Error::InvalidCommand),
+        Command::Export(args) => Ok(export(&args, shell)),
 
-    }
+        Command::Unset(args) => Ok(unset(&args, shell)),
 
-}
+        Command::Shopt(args) => Ok(shopt(&args, shell)),
 
-fn parse(input: &str) -> ShellExec {
+        Command::Jobs(args) => Ok(list_jobs(&args, targets, shell)),
 
-    let tokens = tokenize(input);
+        Command::Fg(args) => fg(&args, targets, shell),
 
-    if let Some((split_point, _)) = tokens
+        Command::Bg(args) => Ok(bg(&args, targets, shell)),
 
-        .iter()
+        Command::Wait(args) => wait(&args, shell),
 
-        .enumerate()
+        Command::Trap(args) => Ok(trap(&args, targets, shell)),
 
-        .find(|&(_, token)| token == "1>" || token == ">")
+        Command::Shift(args) => Ok(shift(&args, shell)),
 
-    {
+        Command::Set(args) => Ok(set(&args, shell)),
 
-        if let Some((left_half, right_half)) = tokens.split_at_checked(split_point) {
+        Command::Let(args) => Ok(let_(&args, shell)),
 
-            let command = &left_half[..left_half.len()];
+        Command::Break(args) => leave_loop("break", &args, shell),
 
-            let file = &right_half[1..];
+        Command::Continue(args) => leave_loop("continue", &args, shell),
 
-            let command = redirected_command(command.to_vec());
+        Command::Read(args) => Ok(read(&args, targets, shell)),
 
-            ShellExec::RedirectedStdOut(command, PathBuf::from(file.join(" ")))
+        Command::Empty => Ok(CommandOutput::Noop),
 
-        } else {
+    }
 
-            ShellExec::PrintToStd(Command::Invalid)
+}
 
+/// The `export` builtin: marks variables for export, optionally assigning them
+/// first. Without names, or with `-p`, lists the exported variables.
+fn export(args: &[String], shell: &mut Shell) -> CommandOutput {
+    let names: Vec<&String> = args.iter().filter(|arg| *arg != "-p").collect();
+    if names.is_empty() {
+        let mut exported: Vec<&String> = shell.exported.iter().collect();
+        exported.sort();
+        let listing: Vec<String> = exported
+            .into_iter()
+            .map(|name| match shell.variables.get(name) {
+                Some(value) => format!("export {}={}", name, double_quote(value)),
+                None => format!("export {}", name),
+            })
+            .collect();
+        return if listing.is_empty() {
+            CommandOutput::Noop
+        } else {
+            CommandOutput::StdOut(listing.join("\n"))
+        };
+    }
+    let mut errors = Vec::new();
+    for arg in names {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        if !is_name(name) {
+            errors.push(format!("export: `{}': not a valid identifier", arg));
+            continue;
         }
+        if let Some(value) = value {
+            shell.variables.insert(name.to_string(), value.to_string());
+        }
+        shell.exported.insert(name.to_string());
+    }
+    if errors.is_empty() {
+        CommandOutput::Noop
+    } else {
+        CommandOutput::StdErr(errors.join("\n"), 1)
+    }
+}
 
-    } else if let Some((split_point, _)) = tokens
-
+/// The `jobs` builtin: lists the given jobs, or all of them, with their state.
+/// `-l` adds their process IDs and `-p` lists only those.
+fn list_jobs(args: &[String], targets: &RedirectedFds, shell: &mut Shell) -> CommandOutput {
+    let long = args.iter().any(|arg| arg == "-l");
+    let pids_only = args.iter().any(|arg| arg == "-p");
+    let specs: Vec<&String> = args.iter().filter(|arg| !arg.starts_with('-')).collect();
+    shell.jobs.update();
+    let mut ids = Vec::new();
+    let mut errors = Vec::new();
+    if specs.is_empty() {
+        ids.extend(shell.jobs.iter().map(|job| job.id));
+    }
+    for spec in specs {
+        match shell.jobs.resolve(Some(spec)) {
+            Ok(id) => ids.push(id),
+            Err(err) => errors.push(format!("jobs: {}", err)),
+        }
+    }
+    let listing: Vec<String> = ids
         .iter()
+        .filter_map(|&id| shell.jobs.get(id))
+        .map(|job| {
+            if pids_only {
+                job.pid.to_string()
+            } else {
+                shell.jobs.describe(job, long)
+            }
+        })
+        .collect();
+    // Finished jobs are only listed once.
+    shell.jobs.remove_finished();
+    listing_output("jobs", listing, errors, targets)
+}
 
-        .enumerate()
-
-        .find(|&(_, token)| token == "1>>" || token == ">>")
-
-    {
-
-        if let Some((left_half, right_half)) = tokens.split_at_checked(split_point) {
-
-            let command = &left_half[..left_half.len()];
-
-            let file = &right_half[1..];
-
-            let command = redirected_command(command.to_vec());
-
-            ShellExec::RedirectedStdOutAppend(command, PathBuf::from(file.join(" ")))
-
+/// The output of a builtin that reports on several things, some of which may
+/// have failed. The lines about the others are written first when there are
+/// errors.
+fn listing_output(
+    name: &str,
+    lines: Vec<String>,
+    mut errors: Vec<String>,
+    targets: &RedirectedFds,
+) -> CommandOutput {
+    if errors.is_empty() {
+        return if lines.is_empty() {
+            CommandOutput::Noop
         } else {
-
-            ShellExec::PrintToStd(Command::Invalid)
-
+            CommandOutput::StdOut(lines.join("\n"))
+        };
+    }
+    if !lines.is_empty() {
+        if let Err(err) = targets.write_line(libc::STDOUT_FILENO, &lines.join("\n")) {
+            errors.push(format!("{}: write error: {}", name, describe_io_error(&err)));
         }
+    }
+    CommandOutput::StdErr(errors.join("\n"), 1)
+}
 
-    } else if let Some((split_point, _)) =
-
-        tokens.iter().enumerate().find(|&(_, token)| token == "2>")
-
-    {
-
-        if let Some((left_half, right_half)) = tokens.split_at_checked(split_point) {
-
-            let command = &left_half[..left_half.len()];
-
-            let file = &right_half[1..];
-
-            let command = redirected_command(command.to_vec());
-
-            ShellExec::RedirectedStdErr(command, PathBuf::from(file.join(" ")))
-
-        } else {
-
-            ShellExec::PrintToStd(Command::Invalid)
-
+/// The `fg` builtin: brings a job to the foreground, continuing it if it is
+/// stopped, and waits for it.
+fn fg(args: &[String], targets: &RedirectedFds, shell: &mut Shell) -> Result<CommandOutput> {
+    let id = match shell.jobs.resolve(args.first().map(String::as_str)) {
+        Ok(id) => id,
+        Err(err) => return Ok(CommandOutput::StdErr(format!("fg: {}", err), 1)),
+    };
+    let job_control = shell.job_control;
+    let Some(job) = shell.jobs.get_mut(id) else {
+        return Ok(CommandOutput::Noop);
+    };
+    // The job goes on even if its command can't be shown.
+    if let Err(err) = targets.write_line(libc::STDOUT_FILENO, &job.command) {
+        eprintln!("fg: write error: {}", describe_io_error(&err));
+    }
+    if job.state == JobState::Stopped {
+        // Hand over the terminal before the job can go on and use it.
+        if let (true, Some(pgid)) = (job_control, job.pgid) {
+            jobs::give_terminal_to(pgid);
         }
+        job.signal(libc::SIGCONT);
+        job.state = JobState::Running;
+    }
+    if job.state == JobState::Running {
+        wait_for_job(job, job_control)?;
+    }
+    let state = job.state;
+    Ok(CommandOutput::Exited(finish_foreground_job(id, state, shell)))
+}
 
-    } else if let Some((split_point, _)) =
-
-        tokens.iter().enumerate().find(|&(_, token)| token == "2>>")
-
-    {
-
-        if let Some((left_half, right_half)) = tokens.split_at_checked(split_point) {
-
-            let command = &left_half[..left_half.len()];
-
-            let file = &right_half[1..];
-
-            let command = redirected_command(command.to_vec());
+/// Updates the job table once a job in the foreground finished or stopped,
+/// and returns its status.
+fn finish_foreground_job(id: usize, state: JobState, shell: &mut Shell) -> i32 {
+    match state {
+        JobState::Done(status) => {
+            shell.jobs.remove(id);
+            status
+        }
+        _ => {
+            shell.jobs.set_state(id, JobState::Stopped);
+            if let Some(job) = shell.jobs.get(id) {
+                eprintln!("\n{}", shell.jobs.describe(job, false));
+            }
+            128 + libc::SIGTSTP
+        }
+    }
+}
 
-            ShellExec::RedirectedStdErrAppend(command, PathBuf::from(file.join(" ")))
+/// The `bg` builtin: continues stopped jobs in the background.
+fn bg(args: &[String], targets: &RedirectedFds, shell: &mut Shell) -> CommandOutput {
+    let specs: Vec<Option<&str>> = if args.is_empty() {
+        vec![None]
+    } else {
+        args.iter().map(|arg| Some(arg.as_str())).collect()
+    };
+    let mut lines = Vec::new();
+    let mut errors = Vec::new();
+    for spec in specs {
+        let id = match shell.jobs.resolve(spec) {
+            Ok(id) => id,
+            Err(err) => {
+                errors.push(format!("bg: {}", err));
+                continue;
+            }
+        };
+        let Some(job) = shell.jobs.get(id) else {
+            continue;
+        };
+        if job.state != JobState::Stopped {
+            errors.push(format!("bg: job {} already in background", id));
+            continue;
+        }
+        job.signal(libc::SIGCONT);
+        lines.push(format!("[{}]{} {} &", id, shell.jobs.marker(id), job.command));
+        shell.jobs.set_state(id, JobState::Running);
+    }
+    listing_output("bg", lines, errors, targets)
+}
 
+/// The `wait` builtin: waits for the given jobs or process IDs and returns the
+/// status of the last one. Without arguments, waits for every running job and
+/// returns 0.
+fn wait(args: &[String], shell: &mut Shell) -> Result<CommandOutput> {
+    if args.is_empty() {
+        let running: Vec<usize> = shell
+            .jobs
+            .iter()
+            .filter(|job| job.state == JobState::Running)
+            .map(|job| job.id)
+            .collect();
+        for id in running {
+            if let Some(job) = shell.jobs.get_mut(id) {
+                job.wait(true)?;
+            }
+        }
+        shell.jobs.remove_finished();
+        return Ok(CommandOutput::Noop);
+    }
+    let mut status = 0;
+    for arg in args {
+        let job = if arg.starts_with('%') {
+            shell.jobs.resolve(Some(arg)).map(|id| shell.jobs.get(id))
         } else {
-
-            ShellExec::PrintToStd(Command::Invalid)
-
+            match arg.parse() {
+                Ok(pid) => Ok(shell.jobs.find_by_pid(pid)),
+                Err(_) => Err(format!("`{}': not a pid or valid job spec", arg)),
+            }
+        };
+        let id = match job {
+            Ok(Some(job)) => job.id,
+            Ok(None) => {
+                eprintln!("wait: pid {} is not a child of this shell", arg);
+                status = 127;
+                continue;
+            }
+            Err(err) => return Ok(CommandOutput::StdErr(format!("wait: {}", err), 1)),
+        };
+        let Some(job) = shell.jobs.get_mut(id) else {
+            continue;
+        };
+        if job.state == JobState::Running {
+            job.wait(true)?;
         }
-
-    } else if let Some((head, tail)) = tokens.split_first() {
-
-        let command = match head.as_str() {
-
-            "echo" => Command::Echo(tail.join(" ")),
-
-            "exit" => Command::Exit(tail.join(" ")),
-
-            "type" => Command::Type(tail.join(" ")),
-
-            "pwd" => Command::Pwd,
-
-            "cd" => Command::Cd(tail.join(" ")),
-
-            c => Command::SysProgram(c.to_owned(), tail.to_vec()),
-
+        status = match job.state {
+            JobState::Done(status) => {
+                shell.jobs.remove(id);
+                status
+            }
+            state => {
+                shell.jobs.set_state(id, state);
+                128 + libc::SIGTSTP
+            }
         };
+    }
+    Ok(CommandOutput::Exited(status))
+}
 
-        ShellExec::PrintToStd(command)
-
+/// The `unset` builtin: removes variables, including from the environment.
+fn unset(args: &[String], shell: &mut Shell) -> CommandOutput {
+    let mut errors = Vec::new();
+    for name in args.iter().filter(|arg| *arg != "-v") {
+        if !is_name(name) {
+            errors.push(format!("unset: `{}': not a valid identifier", name));
+            continue;
+        }
+        shell.variables.remove(name);
+        shell.exported.remove(name);
+    }
+    if errors.is_empty() {
+        CommandOutput::Noop
     } else {
+        CommandOutput::StdErr(errors.join("\n"), 1)
+    }
+}
 
-        ShellExec::PrintToStd(Command::Empty)
+/// The `shopt` builtin: `-s` turns the named options on and `-u` turns them
+/// off. Otherwise prints whether the named options, or all of them, are on.
+fn shopt(args: &[String], shell: &mut Shell) -> CommandOutput {
+    let (setting, names) = match args.split_first() {
+        Some((flag, names)) if flag == "-s" => (Some(true), names),
+        Some((flag, names)) if flag == "-u" => (Some(false), names),
+        _ => (None, args),
+    };
+    let names: Vec<&str> = if names.is_empty() && setting.is_none() {
+        ShellOptions::NAMES.to_vec()
+    } else {
+        names.iter().map(String::as_str).collect()
+    };
+    let mut listing = Vec::new();
+    let mut errors = Vec::new();
+    for name in names {
+        match (shell.options.get_mut(name), setting) {
+            (Some(option), Some(value)) => *option = value,
+            (Some(option), None) => {
+                listing.push(format!("{:<15}\t{}", name, if *option { "on" } else { "off" }));
+            }
+            (None, _) => errors.push(format!("shopt: {}: invalid shell option name", name)),
+        }
+    }
+    if !errors.is_empty() {
+        CommandOutput::StdErr(errors.join("\n"), 1)
+    } else if !listing.is_empty() {
+        CommandOutput::StdOut(listing.join("\n"))
+    } else {
+        CommandOutput::Noop
+    }
+}
 
+/// The `shift` builtin: drops the first `n` positional parameters, 1 by
+/// default, and renumbers the others.
+fn shift(args: &[String], shell: &mut Shell) -> CommandOutput {
+    let count = match args.first().map(|arg| arg.parse::<usize>()) {
+        None => 1,
+        Some(Ok(count)) => count,
+        Some(Err(_)) => {
+            return CommandOutput::StdErr(
+                format!("shift: {}: numeric argument required", args[0]),
+                1,
+            )
+        }
+    };
+    if count > shell.positional.len() {
+        return CommandOutput::Exited(1);
     }
+    shell.positional.drain(..count);
+    CommandOutput::Noop
+}
 
+/// The `let` builtin: evaluates each argument as an arithmetic expression.
+/// Succeeds if the last one is non-zero.
+fn let_(args: &[String], shell: &mut Shell) -> CommandOutput {
+    if args.is_empty() {
+        return CommandOutput::StdErr(String::from("let: expression expected"), 1);
+    }
+    let mut value = 0;
+    for arg in args {
+        match evaluate_arithmetic(arg, shell) {
+            Ok(result) => value = result,
+            Err(message) => return CommandOutput::StdErr(format!("let: {}", message), 1),
+        }
+    }
+    CommandOutput::Exited(if value != 0 { 0 } else { 1 })
 }
 
-fn redirected_command(command: Vec<String>) -> Command {
-
-    if let Some((head, tail)) = command.split_first() {
-
-        match head.as_str() {
+/// The `break` and `continue` builtins: leave the innermost `n` loops, 1 by
+/// default, either for good or for their next round, by unwinding to them.
+fn leave_loop(name: &str, args: &[String], shell: &mut Shell) -> Result<CommandOutput> {
+    let count = match args.first().map(|arg| arg.parse::<usize>()) {
+        None => 1,
+        Some(Ok(count)) if count > 0 => count,
+        Some(Ok(_)) => {
+            let message = format!("{}: {}: loop count out of range", name, args[0]);
+            return Ok(CommandOutput::StdErr(message, 1));
+        }
+        Some(Err(_)) => {
+            let message = format!("{}: {}: numeric argument required", name, args[0]);
+            return Ok(CommandOutput::StdErr(message, 1));
+        }
+    };
+    if shell.loop_depth == 0 {
+        let message = format!(
+            "{}: only meaningful in a `for', `while', or `until' loop",
+            name
+        );
+        return Ok(CommandOutput::StdErr(message, 0));
+    }
+    let count = count.min(shell.loop_depth);
+    Err(match name {
+        "break" => Error::Break(count),
+        _ => Error::Continue(count),
+    })
+}
 
-            "echo" => Command::Echo(tail.join(" ")),
+/// The `read` builtin: reads a line from stdin and assigns its fields, split
+/// on `IFS`, to the named variables, the last one taking the rest of the line,
+/// or the whole line to `REPLY` without names. Unless `-r` is given, a
+/// backslash makes the next character part of a field and joins lines. Fails
+/// at the end of the input.
+fn read(args: &[String], targets: &RedirectedFds, shell: &mut Shell) -> CommandOutput {
+    let (raw, names) = match args.first().map(String::as_str) {
+        Some("-r") => (true, &args[1..]),
+        Some(option) if option.starts_with('-') && option.len() > 1 => {
+            return CommandOutput::StdErr(format!("read: {}: invalid option", option), 2)
+        }
+        _ => (false, args),
+    };
+    if let Some(name) = names.iter().find(|name| !is_name(name)) {
+        return CommandOutput::StdErr(format!("read: `{}': not a valid identifier", name), 1);
+    }
+    let Some(fd) = targets.input(libc::STDIN_FILENO) else {
+        return CommandOutput::StdErr(String::from("read: read error: 0: Bad file descriptor"), 1);
+    };
+    // Each byte, and whether a backslash escaped it.
+    let mut line: Vec<(u8, bool)> = Vec::new();
+    let mut escaped = false;
+    let at_end = loop {
+        let byte = match read_byte(fd) {
+            Ok(Some(byte)) => byte,
+            Ok(None) => break true,
+            Err(err) => return CommandOutput::StdErr(format!("read: read error: {}", err), 1),
+        };
+        match byte {
+            b'\\' if !raw && !escaped => escaped = true,
+            b'\n' if escaped => escaped = false,
+            b'\n' => break false,
+            byte => {
+                line.push((byte, escaped));
+                escaped = false;
+            }
+        }
+    };
+    let ifs = shell
+        .parameter("IFS")
+        .unwrap_or_else(|| String::from(" \t\n"));
+    let is_separator = |&(byte, escaped): &(u8, bool)| {
+        !escaped && byte.is_ascii() && ifs.contains(byte as char)
+    };
+    let is_blank = |c: &(u8, bool)| is_separator(c) && c.0.is_ascii_whitespace();
+    let text = |bytes: &[(u8, bool)]| {
+        String::from_utf8_lossy(&bytes.iter().map(|&(byte, _)| byte).collect::<Vec<_>>())
+            .into_owned()
+    };
+    if names.is_empty() {
+        shell.variables.insert(String::from("REPLY"), text(&line));
+    } else {
+        let mut rest = &line[..];
+        for (i, name) in names.iter().enumerate() {
+            rest = &rest[rest.iter().take_while(|c| is_blank(c)).count()..];
+            if i + 1 == names.len() {
+                let end = rest.len() - rest.iter().rev().take_while(|c| is_blank(c)).count();
+                shell.variables.insert(name.clone(), text(&rest[..end]));
+                break;
+            }
+            let end = rest.iter().position(is_separator).unwrap_or(rest.len());
+            shell.variables.insert(name.clone(), text(&rest[..end]));
+            // A separator is a run of blanks with at most one other IFS
+            // character in it.
+            rest = &rest[end..];
+            rest = &rest[rest.iter().take_while(|c| is_blank(c)).count()..];
+            if rest.first().is_some_and(|c| is_separator(c) && !is_blank(c)) {
+                rest = &rest[1..];
+            }
+        }
+    }
+    CommandOutput::Exited(if at_end { 1 } else { 0 })
+}
 
-            "exit" => Command::Exit(tail.join(" ")), 
+/// Reads a single byte, so that nothing past what is needed is taken from the
+/// input, which the commands after `read` may go on reading.
+fn read_byte(fd: RawFd) -> io::Result<Option<u8>> {
+    let mut byte = 0u8;
+    loop {
+        match unsafe { libc::read(fd, (&mut byte as *mut u8).cast(), 1) } {
+            1 => return Ok(Some(byte)),
+            0 => return Ok(None),
+            _ => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
 
-            "type" => Command::Type(tail.join(" ")),
+/// The `set` builtin: `set -- args...`, or `set args...` when the first one
+/// isn't an option, replaces the positional parameters. Without arguments,
+/// lists the variables.
+fn set(args: &[String], shell: &mut Shell) -> CommandOutput {
+    match args.first().map(String::as_str) {
+        None => {
+            let mut names: Vec<&String> = shell.variables.keys().collect();
+            names.sort();
+            let listing: Vec<String> = names
+                .into_iter()
+                .map(|name| format!("{}={}", name, single_quote(&shell.variables[name])))
+                .collect();
+            CommandOutput::StdOut(listing.join("\n"))
+        }
+        Some("--") => {
+            shell.positional = args[1..].to_vec();
+            CommandOutput::Noop
+        }
+        Some(option) if option.starts_with(['-', '+']) && option.len() > 1 => {
+            CommandOutput::StdErr(format!("set: {}: invalid option", option), 2)
+        }
+        Some(_) => {
+            shell.positional = args.to_vec();
+            CommandOutput::Noop
+        }
+    }
+}
 
-            "pwd" => Command::Pwd,
+/// The `trap` builtin: `trap command condition...` runs the command when one of
+/// the conditions occurs, an empty command ignores the signals and `-` resets
+/// them. Without a command, or with `-p`, lists the traps.
+fn trap(args: &[String], targets: &RedirectedFds, shell: &mut Shell) -> CommandOutput {
+    let args = match args.first() {
+        Some(arg) if arg == "--" => &args[1..],
+        _ => args,
+    };
+    let mut errors = Vec::new();
+    match args.first().map(String::as_str) {
+        None | Some("-p") => {
+            let names = args.get(1..).unwrap_or_default();
+            let conditions: Vec<Condition> = trap_conditions(names, &mut errors)
+                .into_iter()
+                .map(|(_, condition)| condition)
+                .collect();
+            let listing: Vec<String> = shell
+                .traps
+                .iter()
+                .filter(|(condition, _)| conditions.is_empty() || conditions.contains(condition))
+                .map(|(condition, command)| {
+                    format!("trap -- {} {}", single_quote(command), condition)
+                })
+                .collect();
+            return listing_output("trap", listing, errors, targets);
+        }
+        Some(first) => {
+            // A lone condition is reset, like with `-`.
+            let (command, names) = match args {
+                [_] if Condition::parse(first).is_some() => (None, args),
+                [_, names @ ..] if first == "-" => (None, names),
+                [_, names @ ..] => (Some(first), names),
+                [] => (None, args),
+            };
+            if names.is_empty() {
+                return CommandOutput::StdErr(
+                    String::from("trap: usage: trap [-p] [arg signal_spec ...]"),
+                    2,
+                );
+            }
+            for (name, condition) in trap_conditions(names, &mut errors) {
+                let command = command.map(str::to_string);
+                if let Err(err) = shell.traps.set(condition, command, shell.job_control) {
+                    errors.push(format!("trap: {}: {}", name, describe_io_error(&err)));
+                }
+            }
+        }
+    }
+    if errors.is_empty() {
+        CommandOutput::Noop
+    } else {
+        CommandOutput::StdErr(errors.join("\n"), 1)
+    }
+}
 
-            "cd" => Command::Cd(tail.join(" ")),
+/// Reads the conditions given to `trap`, reporting those that aren't valid.
+fn trap_conditions<'a>(
+    names: &'a [String],
+    errors: &mut Vec<String>,
+) -> Vec<(&'a String, Condition)> {
+    names
+        .iter()
+        .filter_map(|name| match Condition::parse(name) {
+            Some(condition) => Some((name, condition)),
+            None => {
+                errors.push(format!("trap: {}: invalid signal specification", name));
+                None
+            }
+        })
+        .collect()
+}
 
-            c => Command::SysProgram(c.to_owned(), tail.to_vec()),
+/// Quotes a value in single quotes so that the shell reads it back unchanged.
+fn single_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
 
+/// Quotes a value so that the shell reads it back unchanged.
+fn double_quote(value: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
         }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
 
-    } else {
-
-        Command::Invalid
-
+/// Parses and runs a script in the current process. A syntax error is reported
+/// and gives status 2, without anything being run.
+fn run_script(script: &str, shell: &mut Shell) -> Result<i32> {
+    match parser::parse(script) {
+        Ok(exec) => run_complete_command(exec, shell),
+        Err(err) => {
+            eprintln!("{}", err.diagnostic(script, 1));
+            Ok(2)
+        }
     }
+}
 
+/// Expands a word into the fields it produces: parameters such as `$NAME`,
+/// `${NAME}` and `$?` and command substitutions such as `$(cmd)` and `` `cmd` ``
+/// are substituted, the results of unquoted substitutions are split on `IFS`,
+/// fields with unquoted `*`, `?` or `[` are replaced by the file names they
+/// match, and quotes and escaping backslashes are removed.
+fn expand_word(word: &str, shell: &mut Shell) -> Result<Vec<String>> {
+    expand(word, shell, true)
 }
 
-fn tokenize(input: &str) -> Vec<String> {
+/// Expands a word that has to stay a single string, such as the value of an
+/// assignment or the target of a redirection. No field splitting is done.
+fn expand_string(word: &str, shell: &mut Shell) -> Result<String> {
+    Ok(expand(word, shell, false)?.concat())
+}
 
-    let mut tokens = Vec::new();
+fn expand(word: &str, shell: &mut Shell, split: bool) -> Result<Vec<String>> {
+    let fields = expand_fields(word, shell, split)?;
+    if !split {
+        return Ok(fields.into_iter().map(|(field, _)| field).collect());
+    }
+    let mut words = Vec::new();
+    for (field, pattern) in fields {
+        let Some(pattern) = pattern.filter(|pattern| has_pattern_chars(pattern)) else {
+            words.push(field);
+            continue;
+        };
+        let paths = glob(&pattern);
+        if !paths.is_empty() {
+            words.extend(paths);
+        } else if shell.options.failglob {
+            return Err(Error::NoMatch(field));
+        } else if !shell.options.nullglob {
+            words.push(field);
+        }
+    }
+    Ok(words)
+}
 
-    let mut current_token = String::new();
+/// Expands a word that is used as a pattern, such as in `${VAR#pattern}`.
+/// Quoted pattern characters match literally.
+fn expand_pattern(word: &str, shell: &mut Shell) -> Result<String> {
+    Ok(expand_fields(word, shell, false)?
+        .into_iter()
+        .map(|(field, pattern)| pattern.unwrap_or_else(|| escape_pattern(&field)))
+        .collect())
+}
 
+/// Expands a word into fields, each with the pattern it is matched against
+/// file names with if it has unquoted pattern characters.
+fn expand_fields(
+    word: &str,
+    shell: &mut Shell,
+    split: bool,
+) -> Result<Vec<(String, Option<String>)>> {
+    let ifs = shell.parameter("IFS").unwrap_or_else(|| String::from(" \t\n"));
+    let mut fields = Fields::default();
     let mut in_single_quote = false;
-
     let mut in_double_quote = false;
-
     let mut in_escape = false;
-
-    for (i, c) in input.chars().enumerate() {
-
+    let mut chars = word.chars().peekable();
+    while let Some(c) = chars.next() {
+        fields.quoted = in_single_quote || in_double_quote || in_escape;
         match c {
-
             '\\' if in_escape => {
-
-                current_token.push(c);
-
+                fields.push(c);
                 in_escape = false;
-
             }
-
             '\\' if !in_single_quote && !in_double_quote => in_escape = true,
-
             '\\' if in_double_quote => {
-
-                if let Some(next_char) = input.chars().nth(i + 1) {
-
-                    if next_char == '$'
-
-                        || next_char == '\\'
-
-                        || next_char == '"'
-
-                        || next_char == '\n'
-
-                    {
-
+                if let Some(next_char) = chars.peek() {
+                    if matches!(next_char, '$' | '`' | '\\' | '"' | '\n') {
                         in_escape = true;
-
                     } else {
-
-                        current_token.push(c);
-
+                        fields.push(c);
                     }
-
                 }
-
             }
-
             '\'' if in_escape => {
-
-                current_token.push(c);
-
+                fields.push(c);
+                in_escape = false;
+            }
+            '\'' if in_single_quote => in_single_quote = false,
+            '\'' if !in_double_quote => {
+                in_single_quote = true;
+                fields.start();
+            }
+            '"' if in_escape => {
+                fields.push(c);
+                in_escape = false;
+            }
+            '"' if in_double_quote => in_double_quote = false,
+            '"' if !in_single_quote => {
+                in_double_quote = true;
+                fields.start();
+            }
+            '\n' if in_escape => in_escape = false,
+            '$' if !in_escape && !in_single_quote && chars.peek() == Some(&'(') => {
+                chars.next();
+                match read_substitution(&mut chars) {
+                    Ok(script) => {
+                        let output = substitution(&script, shell)?;
+                        fields.push_expansion(&output, split && !in_double_quote, &ifs);
+                    }
+                    Err(script) => {
+                        fields.push_str("$(");
+                        fields.push_str(&script);
+                    }
+                }
+            }
+            '$' if !in_escape && !in_single_quote => match read_parameter(&mut chars) {
+                // `"$@"` gives a field for each positional parameter, as do
+                // `$@` and `$*` unquoted, with each of them split on its own.
+                Some(name) if split && (name == "@" || (name == "*" && !in_double_quote)) => {
+                    fields.push_each(&shell.positional, in_double_quote, &ifs);
+                }
+                Some(name) => {
+                    let value = shell.parameter(&name).unwrap_or_default();
+                    fields.push_expansion(&value, split && !in_double_quote, &ifs);
+                }
+                None if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    match read_braced(&mut chars, in_double_quote) {
+                        Ok(body) => match expand_parameter(&body, in_double_quote, shell)? {
+                            Parameter::Value(value) => {
+                                fields.push_expansion(&value, split && !in_double_quote, &ifs);
+                            }
+                            Parameter::Word(word) if in_double_quote => {
+                                fields.push_str(&expand_operand(&word, true, shell)?);
+                            }
+                            Parameter::Word(word) => {
+                                fields.push_fields(expand_fields(&word, shell, split)?);
+                            }
+                            Parameter::Each(values) if split => {
+                                fields.push_each(&values, in_double_quote, &ifs);
+                            }
+                            Parameter::Each(values) => fields.push_str(&values.join(" ")),
+                        },
+                        Err(body) => {
+                            fields.push_str("${");
+                            fields.push_str(&body);
+                        }
+                    }
+                }
+                None => fields.push(c),
+            },
+            '`' if !in_escape && !in_single_quote => match read_backquoted(&mut chars) {
+                Ok(script) => {
+                    let output = command_substitution(&unescape_backquoted(&script), shell)?;
+                    fields.push_expansion(&output, split && !in_double_quote, &ifs);
+                }
+                Err(script) => {
+                    fields.push(c);
+                    fields.push_str(&script);
+                }
+            },
+            // Only the word of an operator like `${VAR-word}` has unquoted
+            // blanks, which split it as they would the value of a parameter.
+            c if split && !fields.quoted && c.is_whitespace() => {
+                fields.push_split(&c.to_string(), &ifs);
+            }
+            _ => {
+                fields.push(c);
                 in_escape = false;
-
             }
+        }
+    }
+    Ok(fields.finish())
+}
 
-            '\'' if in_single_quote => {
+/// Expands a pattern into the sorted paths of the existing files it matches.
+/// Backslashes in the pattern make the next character match literally, and
+/// file names starting with `.` only match a pattern that starts with `.`.
+fn glob(pattern: &str) -> Vec<String> {
+    let (mut paths, components) = match pattern.strip_prefix('/') {
+        Some(components) => (vec![String::from("/")], components),
+        None => (vec![String::new()], pattern),
+    };
+    for component in components.split('/') {
+        let mut matches = Vec::new();
+        for base in paths {
+            if component.is_empty() {
+                if Path::new(&base).is_dir() {
+                    matches.push(format!("{}/", base));
+                }
+            } else if has_pattern_chars(component) {
+                let dir = if base.is_empty() { "." } else { base.as_str() };
+                let Ok(entries) = fs::read_dir(dir) else {
+                    continue;
+                };
+                let pattern: Vec<char> = component.chars().collect();
+                for entry in entries.flatten() {
+                    let Ok(name) = entry.file_name().into_string() else {
+                        continue;
+                    };
+                    let name_chars: Vec<char> = name.chars().collect();
+                    if (!name.starts_with('.') || component.starts_with('.'))
+                        && matches_pattern(&pattern, &name_chars)
+                    {
+                        matches.push(join_path(&base, &name));
+                    }
+                }
+            } else {
+                let path = join_path(&base, &unescape_pattern(component));
+                if fs::symlink_metadata(&path).is_ok() {
+                    matches.push(path);
+                }
+            }
+        }
+        paths = matches;
+    }
+    paths.sort();
+    paths
+}
 
-                in_single_quote = false;
+fn join_path(base: &str, name: &str) -> String {
+    if base.is_empty() || base.ends_with('/') {
+        format!("{}{}", base, name)
+    } else {
+        format!("{}/{}", base, name)
+    }
+}
 
+/// Whether a pattern contains an unescaped `*`, `?`, or `[` with a `]` after it.
+fn has_pattern_chars(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
             }
+            '*' | '?' => return true,
+            '[' if chars.clone().any(|c| c == ']') => return true,
+            _ => (),
+        }
+    }
+    false
+}
 
-            '\'' if !in_double_quote => in_single_quote = true,
+fn unescape_pattern(pattern: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
 
-            '"' if in_escape => {
+/// Escapes the pattern characters in a string so that it matches only itself.
+fn escape_pattern(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
 
-                current_token.push(c);
+/// Matches a whole name against a pattern, where `*` matches any string, `?`
+/// any character and `[...]` any character in the bracket expression.
+fn matches_pattern(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| matches_pattern(rest, &name[i..])),
+        Some(('?', rest)) => !name.is_empty() && matches_pattern(rest, &name[1..]),
+        Some(('[', rest)) => match name.split_first() {
+            Some((&c, name_rest)) => match match_bracket(rest, c) {
+                Some((matched, len)) => matched && matches_pattern(&rest[len..], name_rest),
+                // An unterminated bracket is an ordinary character.
+                None => c == '[' && matches_pattern(rest, name_rest),
+            },
+            None => false,
+        },
+        Some(('\\', [escaped, rest @ ..])) => {
+            name.first() == Some(escaped) && matches_pattern(rest, &name[1..])
+        }
+        Some((c, rest)) => name.first() == Some(c) && matches_pattern(rest, &name[1..]),
+    }
+}
 
-                in_escape = false;
+/// Matches a character against the bracket expression at the start of
+/// `pattern`, which follows its `[`. Returns whether it matched and the length
+/// of the expression up to and including its `]`, or `None` if it has no `]`.
+fn match_bracket(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let negated = matches!(pattern.first(), Some('!' | '^'));
+    let mut i = usize::from(negated);
+    let start = i;
+    let mut matched = false;
+    loop {
+        let mut low = *pattern.get(i)?;
+        // A `]` right at the start is part of the set rather than its end.
+        if low == ']' && i > start {
+            return Some((matched != negated, i + 1));
+        }
+        if low == '\\' {
+            i += 1;
+            low = *pattern.get(i)?;
+        }
+        i += 1;
+        let mut high = low;
+        if pattern.get(i) == Some(&'-') && pattern.get(i + 1).is_some_and(|&c| c != ']') {
+            i += 1;
+            high = pattern[i];
+            if high == '\\' {
+                i += 1;
+                high = *pattern.get(i)?;
+            }
+            i += 1;
+        }
+        matched |= low <= c && c <= high;
+    }
+}
 
+/// Expands the body of a here-document whose delimiter wasn't quoted.
+/// Parameters and command substitutions are substituted, and a backslash only
+/// escapes `$`, `` ` ``, `\` and newlines. Quotes are ordinary characters.
+fn expand_here_document(body: &str, shell: &mut Shell) -> Result<String> {
+    let mut expanded = String::new();
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                Some('$' | '`' | '\\') => expanded.extend(chars.next()),
+                Some('\n') => {
+                    chars.next();
+                }
+                _ => expanded.push(c),
+            },
+            '$' if chars.peek() == Some(&'(') => {
+                chars.next();
+                match read_substitution(&mut chars) {
+                    Ok(script) => expanded.push_str(&substitution(&script, shell)?),
+                    Err(script) => {
+                        expanded.push_str("$(");
+                        expanded.push_str(&script);
+                    }
+                }
             }
+            '$' => match read_parameter(&mut chars) {
+                Some(name) => expanded.push_str(&shell.parameter(&name).unwrap_or_default()),
+                None if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    match read_braced(&mut chars, true) {
+                        Ok(body) => match expand_parameter(&body, true, shell)? {
+                            Parameter::Value(value) => expanded.push_str(&value),
+                            Parameter::Word(word) => {
+                                expanded.push_str(&expand_operand(&word, true, shell)?)
+                            }
+                            Parameter::Each(values) => expanded.push_str(&values.join(" ")),
+                        },
+                        Err(body) => {
+                            expanded.push_str("${");
+                            expanded.push_str(&body);
+                        }
+                    }
+                }
+                None => expanded.push(c),
+            },
+            '`' => match read_backquoted(&mut chars) {
+                Ok(script) => {
+                    let output = command_substitution(&unescape_backquoted(&script), shell)?;
+                    expanded.push_str(&output);
+                }
+                Err(script) => {
+                    expanded.push(c);
+                    expanded.push_str(&script);
+                }
+            },
+            _ => expanded.push(c),
+        }
+    }
+    Ok(expanded)
+}
 
-            '"' if in_double_quote => {
+/// Stores the input of a here-document or here-string in a temporary file that
+/// is already unlinked, ready to be read from the start.
+fn here_document_file(text: &str) -> io::Result<File> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let path = env::temp_dir().join(format!(
+        "shell-here-{}-{}",
+        process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+    file.write_all(text.as_bytes())?;
+    file.rewind()?;
+    Ok(file)
+}
 
-                in_double_quote = false;
+/// Describes an I/O error the way the C library does, without the
+/// `(os error N)` that Rust appends.
+fn describe_io_error(err: &io::Error) -> String {
+    let message = err.to_string();
+    match message.find(" (os error") {
+        Some(end) => message[..end].to_string(),
+        None => message,
+    }
+}
 
+/// Inside backquotes a backslash only escapes `$`, `` ` `` and another
+/// backslash; those backslashes are removed before the script runs.
+fn unescape_backquoted(script: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = script.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(&next_char)) if matches!(next_char, '$' | '`' | '\\') => {
+                unescaped.push(next_char);
+                chars.next();
             }
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
 
-            '"' if !in_single_quote => in_double_quote = true,
+/// Substitutes the script read for a `$(...)`: evaluates it if it is a
+/// `$((...))` arithmetic expansion, and runs it otherwise.
+fn substitution(script: &str, shell: &mut Shell) -> Result<String> {
+    match arithmetic_expression(script) {
+        Some(expression) => {
+            let expression = expand_string(expression, shell)?;
+            let value = evaluate_arithmetic(&expression, shell)
+                .map_err(|message| arithmetic_error(message, shell))?;
+            Ok(value.to_string())
+        }
+        None => command_substitution(script, shell),
+    }
+}
 
-            ' ' | '\t' if in_escape => {
+/// The error for an arithmetic expansion that failed. A script can't go on
+/// without the value, so it exits.
+fn arithmetic_error(message: String, shell: &Shell) -> Error {
+    if shell.interactive {
+        return Error::Expansion(message);
+    }
+    eprintln!("{}", message);
+    Error::Exit(1)
+}
 
-                current_token.push(c);
+/// Evaluates an arithmetic expression that is already expanded. An error
+/// names the expression.
+fn evaluate_arithmetic(expression: &str, shell: &mut Shell) -> std::result::Result<i64, String> {
+    arithmetic::evaluate(expression, &mut shell.variables)
+        .map_err(|err| format!("{}: {}", expression, err))
+}
 
-                in_escape = false;
+/// Runs a script in a forked subshell and returns what it wrote to stdout, with
+/// trailing newlines removed.
+fn command_substitution(script: &str, shell: &mut Shell) -> Result<String> {
+    let (read_end, write_end) = pipe()?;
+    // The substitution stays in the shell's process group, and so do the
+    // commands it runs, so an interrupt from the terminal reaches the shell too.
+    let interrupt = shell
+        .job_control
+        .then(|| unsafe { libc::signal(libc::SIGINT, libc::SIG_IGN) });
+    let restore_interrupt = || {
+        if let Some(handler) = interrupt {
+            unsafe { libc::signal(libc::SIGINT, handler) };
+        }
+    };
+    match unsafe { libc::fork() } {
+        -1 => {
+            restore_interrupt();
+            Err(io::Error::last_os_error().into())
+        }
+        0 => {
+            restore_interrupt();
+            drop(read_end);
+            redirect_fd(write_end, libc::STDOUT_FILENO);
+            shell.job_control = false;
+            shell.traps.reset_for_subshell();
+            unsafe { libc::signal(libc::SIGPIPE, libc::SIG_DFL) };
+            let status = subshell_status(run_script(script, shell));
+            let _ = io::stdout().flush();
+            unsafe { libc::_exit(status) }
+        }
+        pid => {
+            drop(write_end);
+            let mut output = Vec::new();
+            let read = File::from(read_end).read_to_end(&mut output);
+            let status = wait_for(pid);
+            restore_interrupt();
+            read?;
+            shell.substitution_status = Some(status?);
+            let output = String::from_utf8_lossy(&output);
+            Ok(output.trim_end_matches('\n').to_string())
+        }
+    }
+}
 
+/// Evaluates a `${...}` expansion with an operator, given what is inside the
+/// braces: `${#VAR}` for the length of the value, `${VAR:-word}`, `${VAR:=word}`,
+/// `${VAR:?word}` and `${VAR:+word}` for a default, an assigned default, an
+/// error or an alternative, where without the colon only an unset parameter
+/// counts as missing, `${VAR#pattern}`, `${VAR##pattern}`, `${VAR%pattern}` and
+/// `${VAR%%pattern}` for the value without its shortest or longest matching
+/// prefix or suffix, `${VAR/pattern/string}` for the value with matches
+/// replaced, and `${VAR:offset:length}` for a substring, with arithmetic for
+/// its offset and length, or positional parameters for `${@:offset:length}`.
+/// Words are expanded as if double-quoted when the expansion is. Anything else
+/// is a bad substitution.
+fn expand_parameter(body: &str, quoted: bool, shell: &mut Shell) -> Result<Parameter> {
+    if let Some(name) = body.strip_prefix('#') {
+        if !name.is_empty() && parameter_name_len(name) == name.len() {
+            let length = match name {
+                "@" | "*" => shell.positional.len(),
+                _ => shell.parameter(name).unwrap_or_default().chars().count(),
+            };
+            return Ok(Parameter::Value(length.to_string()));
+        }
+    }
+    let bad_substitution = || Error::Expansion(format!("${{{}}}: bad substitution", body));
+    let (name, operation) = body.split_at(parameter_name_len(body));
+    if name.is_empty() || operation == ":" {
+        return Err(bad_substitution());
+    }
+    // `${@:offset:length}` picks positional parameters, where `$0` comes first.
+    if let ("@" | "*", Some(word)) = (name, operation.strip_prefix(':')) {
+        if !word.starts_with(['-', '=', '?', '+']) {
+            let (offset, length) = substring_range(word, shell)?;
+            if let Some(length) = length.filter(|length| *length < 0) {
+                return Err(Error::Expansion(format!("{}: substring expression < 0", length)));
+            }
+            let parameters: Vec<String> =
+                iter::once(shell.name.clone()).chain(shell.positional.iter().cloned()).collect();
+            let values = slice(&parameters, offset, length).unwrap_or_default();
+            return Ok(if name == "*" && quoted {
+                Parameter::Value(shell.join_fields(values))
+            } else {
+                Parameter::Each(values.to_vec())
+            });
+        }
+    }
+    let value = shell.parameter(name);
+    let (colon, operation) = match operation.strip_prefix(':') {
+        Some(rest) if rest.starts_with(['-', '=', '?', '+']) => (true, rest),
+        _ => (false, operation),
+    };
+    let missing = value.as_ref().map_or(true, |value| colon && value.is_empty());
+    let mut operator = operation.chars();
+    let Some(op) = operator.next() else {
+        return Ok(Parameter::Value(value.unwrap_or_default()));
+    };
+    let word = operator.as_str();
+    let value = match op {
+        '-' | '=' | '?' | '+' if !missing => match op {
+            '+' => return Ok(Parameter::Word(word.to_string())),
+            _ => value.unwrap_or_default(),
+        },
+        '+' => String::new(),
+        '-' => return Ok(Parameter::Word(word.to_string())),
+        '=' => {
+            if !is_name(name) {
+                return Err(Error::Expansion(format!("${}: cannot assign in this way", name)));
             }
+            let value = expand_operand(word, quoted, shell)?;
+            shell.variables.insert(name.to_string(), value.clone());
+            value
+        }
+        '?' => {
+            let message = match word {
+                "" => String::from("parameter null or not set"),
+                word => expand_operand(word, quoted, shell)?,
+            };
+            let message = format!("{}: {}", name, message);
+            // A script can't go on without the parameter.
+            if !shell.interactive {
+                eprintln!("{}", message);
+                return Err(Error::Exit(1));
+            }
+            return Err(Error::Expansion(message));
+        }
+        '#' | '%' => {
+            let longest = word.starts_with(op);
+            let pattern = if longest { &word[1..] } else { word };
+            let pattern = expand_pattern(pattern, shell)?;
+            trim_pattern(&value.unwrap_or_default(), &pattern, op == '%', longest)
+        }
+        '/' => {
+            let (anchor, word) = match word.chars().next() {
+                Some(anchor @ ('/' | '#' | '%')) => (Some(anchor), &word[1..]),
+                _ => (None, word),
+            };
+            let (pattern, replacement) = split_replacement(word);
+            let pattern = expand_pattern(pattern, shell)?;
+            let replacement = expand_operand(replacement, quoted, shell)?;
+            let value = value.unwrap_or_default();
+            replace_pattern(&value, &pattern, &replacement, anchor)
+        }
+        ':' => {
+            let (offset, length) = substring_range(word, shell)?;
+            substring(&value.unwrap_or_default(), offset, length)
+                .ok_or_else(|| Error::Expansion(format!("{}: substring expression < 0", name)))?
+        }
+        _ => return Err(bad_substitution()),
+    };
+    Ok(Parameter::Value(value))
+}
 
-            ' ' | '\t' if !in_single_quote && !in_double_quote => {
+/// What a `${...}` expansion gives: a value, the word of its `-` or `+`
+/// operator, which is expanded in its place so that its quotes decide how it
+/// is split, or positional parameters that each make a field, like `$@`.
+enum Parameter {
+    Value(String),
+    Word(String),
+    Each(Vec<String>),
+}
 
-                if !current_token.is_empty() {
+/// Evaluates the offset and the optional length of a `${VAR:offset:length}`
+/// expansion, given what follows the first colon.
+fn substring_range(word: &str, shell: &mut Shell) -> Result<(i64, Option<i64>)> {
+    let (offset, length) = match word.split_once(':') {
+        Some((offset, length)) => (offset, Some(length)),
+        None => (word, None),
+    };
+    let mut evaluate = |expression: &str| {
+        let expression = expand_string(expression, shell)?;
+        evaluate_arithmetic(&expression, shell).map_err(|message| arithmetic_error(message, shell))
+    };
+    let offset = evaluate(offset)?;
+    let length = length.map(&mut evaluate).transpose()?;
+    Ok((offset, length))
+}
 
-                    tokens.push(current_token.clone());
+/// Expands the word of a `${...}` operator, as if double-quoted when the
+/// expansion is, so that quotes inside it stay.
+fn expand_operand(word: &str, quoted: bool, shell: &mut Shell) -> Result<String> {
+    if quoted {
+        expand_string(&format!("\"{}\"", word), shell)
+    } else {
+        expand_string(word, shell)
+    }
+}
 
-                    current_token.clear();
+/// The length of the parameter name at the start of a `${...}` expansion: a
+/// special parameter, a number or a variable name.
+fn parameter_name_len(body: &str) -> usize {
+    match body.chars().next() {
+        Some('?' | '!' | '#' | '@' | '*' | '$' | '-') => 1,
+        Some(c) if c.is_ascii_digit() => body.chars().take_while(char::is_ascii_digit).count(),
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => body
+            .chars()
+            .take_while(|&c| c == '_' || c.is_ascii_alphanumeric())
+            .count(),
+        _ => 0,
+    }
+}
 
-                }
+/// Removes the shortest or longest prefix, or suffix, of a value that matches
+/// a pattern.
+fn trim_pattern(value: &str, pattern: &str, suffix: bool, longest: bool) -> String {
+    let value: Vec<char> = value.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+    let mut lengths: Vec<usize> = (0..=value.len()).collect();
+    if longest {
+        lengths.reverse();
+    }
+    for length in lengths {
+        if suffix && matches_pattern(&pattern, &value[value.len() - length..]) {
+            return value[..value.len() - length].iter().collect();
+        }
+        if !suffix && matches_pattern(&pattern, &value[..length]) {
+            return value[length..].iter().collect();
+        }
+    }
+    value.iter().collect()
+}
 
+/// Splits the word of `${VAR/pattern/string}` at the first unescaped `/`.
+fn split_replacement(word: &str) -> (&str, &str) {
+    let mut escaped = false;
+    for (i, c) in word.char_indices() {
+        match c {
+            '/' if !escaped => return (&word[..i], &word[i + 1..]),
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+    (word, "")
+}
+
+/// Replaces the longest matches of a pattern in a value: only the first one,
+/// every one with the `/` anchor, or one at the start or end with `#` or `%`.
+fn replace_pattern(value: &str, pattern: &str, replacement: &str, anchor: Option<char>) -> String {
+    let value: Vec<char> = value.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+    let rest = |start: usize| value[start..].iter().collect::<String>();
+    // An empty pattern matches at the anchor, and nowhere without one.
+    if pattern.is_empty() && !matches!(anchor, Some('#' | '%')) {
+        return rest(0);
+    }
+    match anchor {
+        Some('#') => match (0..=value.len())
+            .rev()
+            .find(|&end| matches_pattern(&pattern, &value[..end]))
+        {
+            Some(end) => format!("{}{}", replacement, rest(end)),
+            None => rest(0),
+        },
+        Some('%') => match (0..=value.len())
+            .find(|&start| matches_pattern(&pattern, &value[start..]))
+        {
+            Some(start) => format!("{}{}", value[..start].iter().collect::<String>(), replacement),
+            None => rest(0),
+        },
+        _ => {
+            let mut replaced = String::new();
+            let mut start = 0;
+            while start < value.len() {
+                let end = (start + 1..=value.len())
+                    .rev()
+                    .find(|&end| matches_pattern(&pattern, &value[start..end]));
+                let Some(end) = end else {
+                    replaced.push(value[start]);
+                    start += 1;
+                    continue;
+                };
+                replaced.push_str(replacement);
+                start = end;
+                if anchor != Some('/') {
+                    break;
+                }
             }
+            replaced + &rest(start)
+        }
+    }
+}
 
-            ' ' | '\t' => current_token.push(c),
+/// The substring of a value starting at a character offset, counted from the
+/// end if negative, with at most `length` characters, or up to `length`
+/// characters from the end if negative. `None` if that ends before it starts.
+fn substring(value: &str, offset: i64, length: Option<i64>) -> Option<String> {
+    let chars: Vec<char> = value.chars().collect();
+    slice(&chars, offset, length).map(|chars| chars.iter().collect())
+}
 
-            '\n' if in_escape => in_escape = false,
+/// The items of a slice picked like the characters of a substring.
+fn slice<T>(items: &[T], offset: i64, length: Option<i64>) -> Option<&[T]> {
+    let len = items.len() as i64;
+    let start = if offset < 0 { len + offset } else { offset };
+    if start < 0 || start > len {
+        return Some(&[]);
+    }
+    let end = match length {
+        None => len,
+        Some(length) if length < 0 => len + length,
+        Some(length) => (start + length).min(len),
+    };
+    if end < start {
+        return None;
+    }
+    Some(&items[start as usize..end as usize])
+}
 
-            _ => {
+/// Reads the name of the parameter following a `$`, either bare or enclosed in
+/// braces. Returns `None` if the `$` does not start a parameter expansion.
+fn read_parameter(chars: &mut Peekable<Chars>) -> Option<String> {
+    match chars.peek() {
+        Some(&c @ ('?' | '!' | '#' | '@' | '*' | '$' | '-' | '0'..='9')) => {
+            chars.next();
+            Some(c.to_string())
+        }
+        Some('{') => {
+            let mut lookahead = chars.clone();
+            lookahead.next();
+            let mut name = String::new();
+            while let Some(c) = lookahead.next() {
+                if c == '}' {
+                    let digits = !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit());
+                    let special = matches!(name.as_str(), "?" | "!" | "#" | "@" | "*" | "$" | "-");
+                    if !special && !digits && !is_name(&name) {
+                        return None;
+                    }
+                    *chars = lookahead;
+                    return Some(name);
+                }
+                name.push(c);
+            }
+            None
+        }
+        Some(&c) if c == '_' || c.is_ascii_alphabetic() => {
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if c != '_' && !c.is_ascii_alphanumeric() {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+            Some(name)
+        }
+        _ => None,
+    }
+}
 
-                current_token.push(c);
+/// Splits a `NAME=value` word into its name and (still unexpanded) value.
+fn assignment(word: &str) -> Option<(&str, &str)> {
+    word.split_once('=').filter(|(name, _)| is_name(name))
+}
 
-                in_escape = false;
+/// Collects the fields that a word expands into, along with the pattern each
+/// field is matched against file names with if it has unquoted pattern
+/// characters.
+#[derive(Debug, Default)]
+struct Fields {
+    fields: Vec<(String, Option<String>)>,
+    current: String,
+    /// `current` as a pattern, with quoted pattern characters escaped.
+    pattern: String,
+    /// Whether `pattern` has unquoted pattern characters.
+    globbing: bool,
+    /// Whether the characters being pushed are quoted.
+    quoted: bool,
+    /// Whether `current` is a field even while empty, as after `""`.
+    started: bool,
+    /// Whether the last character split on was IFS whitespace.
+    after_whitespace: bool,
+}
 
-            }
+impl Fields {
+    fn start(&mut self) {
+        self.started = true;
+        self.after_whitespace = false;
+    }
 
+    fn push(&mut self, c: char) {
+        self.current.push(c);
+        if !self.quoted {
+            self.globbing |= matches!(c, '*' | '?' | '[');
+        } else if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            self.pattern.push('\\');
         }
+        self.pattern.push(c);
+        self.start();
+    }
 
+    fn push_str(&mut self, s: &str) {
+        for c in s.chars() {
+            self.push(c);
+        }
+        self.start();
     }
 
-    // Don't forget to add the last word if there is one
+    /// Appends the result of a parameter expansion or command substitution,
+    /// which is subject to field splitting if `split` is set.
+    fn push_expansion(&mut self, value: &str, split: bool, ifs: &str) {
+        if split {
+            self.push_split(value, ifs);
+        } else {
+            self.push_str(value);
+        }
+    }
 
-    if !current_token.is_empty() {
+    /// Appends the result of an unquoted expansion, starting a new field at
+    /// every `IFS` character. Runs of IFS whitespace count as one separator.
+    fn push_split(&mut self, value: &str, ifs: &str) {
+        for c in value.chars() {
+            if !ifs.contains(c) {
+                self.push(c);
+            } else if c.is_whitespace() {
+                if self.started {
+                    self.delimit();
+                }
+                self.after_whitespace = true;
+            } else {
+                if !self.after_whitespace {
+                    self.delimit();
+                }
+                self.after_whitespace = false;
+            }
+        }
+    }
 
-        tokens.push(current_token);
+    /// Appends the positional parameters for `$@`, each one in a field of its
+    /// own. Unquoted, each one is also split and empty ones vanish.
+    fn push_each(&mut self, values: &[String], quoted: bool, ifs: &str) {
+        // `"$@"` without parameters makes no field, unless there is more to it.
+        if values.is_empty() && quoted && self.current.is_empty() {
+            self.started = false;
+        }
+        for (i, value) in values.iter().enumerate() {
+            if i > 0 && (quoted || self.started) {
+                self.delimit();
+            }
+            self.push_expansion(value, !quoted, ifs);
+        }
+    }
 
+    /// Appends the fields of an expanded word, the first one joined to the
+    /// current field.
+    fn push_fields(&mut self, fields: Vec<(String, Option<String>)>) {
+        for (i, (field, pattern)) in fields.into_iter().enumerate() {
+            if i > 0 {
+                self.delimit();
+            }
+            match pattern {
+                Some(pattern) => {
+                    self.pattern.push_str(&pattern);
+                    self.globbing = true;
+                }
+                None => self.pattern.push_str(&escape_pattern(&field)),
+            }
+            self.current.push_str(&field);
+            self.start();
+        }
     }
 
-    tokens
+    fn delimit(&mut self) {
+        let pattern = std::mem::take(&mut self.pattern);
+        let pattern = std::mem::take(&mut self.globbing).then_some(pattern);
+        self.fields.push((std::mem::take(&mut self.current), pattern));
+        self.started = false;
+    }
 
+    fn finish(mut self) -> Vec<(String, Option<String>)> {
+        if self.started {
+            self.delimit();
+        }
+        self.fields
+    }
 }
 
 fn find_executable_on_path(path: &str, executable: &str) -> Result<Option<PathBuf>> {
-
+    // A name containing a slash is already a path and is not searched for.
+    if executable.contains('/') {
+        return Ok(Some(PathBuf::from(executable)).filter(|path| path.exists()));
+    }
     Ok(path
-
         .split(":")
-
         .map(|dir| Path::new(dir).join(executable))
-
-        .find(|path| fs::metadata(path).is_ok()))
-
+        .find(|path| is_executable(path)))
 }
 
-fn run_executable_with_args(program: &PathBuf, args: &[String]) -> io::Result<Output> {
-
-    std::process::Command::new(program)
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| {
+        metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+    })
+}
 
-        .arg0(program.file_name().unwrap().to_str().unwrap())
+/// Runs an external program in the foreground until it finishes or stops.
+/// With job control, it runs in a process group of its own.
+fn run_executable(
+    mut command: std::process::Command,
+    text: String,
+    shell: &mut Shell,
+) -> io::Result<i32> {
+    if shell.job_control {
+        unsafe { command.pre_exec(|| jobs::enter_job(0, true)) };
+    }
+    let pid = command.spawn()?.id() as libc::pid_t;
+    let pgid = shell.job_control.then(|| {
+        unsafe { libc::setpgid(pid, pid) };
+        pid
+    });
+    run_in_foreground(Job::new(vec![pid], pgid, text), shell)
+}
 
+/// Prepares the process for an external program. Its stdin, stdout and stderr
+/// are inherited from the shell unless redirected, so output shows up as soon
+/// as the program writes it and interactive programs can use the terminal.
+/// The program sees exactly the given environment, and keeps ignoring the
+/// signals ignored with `trap ''`. It gets `name`, the command as typed, as its
+/// `argv[0]`. A directory can't be run.
+fn executable_command(
+    name: &str,
+    program: &Path,
+    args: &[String],
+    targets: &RedirectedFds,
+    environment: &[(String, String)],
+) -> io::Result<std::process::Command> {
+    if program.is_dir() {
+        return Err(io::Error::from_raw_os_error(libc::EISDIR));
+    }
+    let mut command = std::process::Command::new(program);
+    command
+        .arg0(name)
         .args(args)
-
-        .stdout(Stdio::piped())
-
-        .output()
-
+        .env_clear()
+        .envs(environment.iter().map(|(name, value)| (name, value)));
+    unsafe { command.pre_exec(traps::ignore_in_child) };
+    if !targets.fds.is_empty() {
+        let targets = RedirectedFds {
+            fds: targets
+                .fds
+                .iter()
+                .map(|(&fd, target)| Ok((fd, target.as_ref().map(|t| t.try_clone()).transpose()?)))
+                .collect::<io::Result<_>>()?,
+        };
+        unsafe { command.pre_exec(move || targets.apply()) };
+    }
+    Ok(command)
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
 
 #[derive(Debug)]
+pub enum Error {
 
-#[allow(dead_code)]
+    /// Raised by the `exit` builtin to unwind to the REPL, which then ends the
+    /// session with the given status.
+    Exit(i32),
 
-pub enum Error {
+    /// Raised by the `break` builtin to unwind out of the given number of
+    /// loops.
+    Break(usize),
 
-    InvalidCommand,
+    /// Raised by the `continue` builtin to unwind to the next round of the
+    /// loop the given number of loops out.
+    Continue(usize),
 
-    EncodingError(FromUtf8Error),
+    /// A pattern matched no files while the `failglob` option was set.
+    NoMatch(String),
 
-    EnvVarError(VarError),
+    /// An expansion that fails, such as `${VAR:?}` with `VAR` unset or
+    /// arithmetic that divides by zero.
+    Expansion(String),
 
     Io(std::io::Error),
 
@@ -946,16 +3118,6 @@ pub enum Error {
 
 }
 
-impl From<VarError> for Error {
-
-    fn from(value: VarError) -> Self {
-
-        Self::EnvVarError(value)
-
-    }
-
-}
-
 impl From<std::io::Error> for Error {
 
     fn from(value: std::io::Error) -> Self {
@@ -966,16 +3128,6 @@ impl From<std::io::Error> for Error {
 
 }
 
-impl From<FromUtf8Error> for Error {
-
-    fn from(value: FromUtf8Error) -> Self {
-
-        Self::EncodingError(value)
-
-    }
-
-}
-
 impl From<ReadlineError> for Error {
 
     fn from(value: ReadlineError) -> Self {
@@ -990,7 +3142,15 @@ impl core::fmt::Display for Error {
 
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 
-        write!(fmt, "{self:?}")
+        match self {
+            Error::Exit(status) => write!(fmt, "exit {}", status),
+            Error::Break(count) => write!(fmt, "break {}", count),
+            Error::Continue(count) => write!(fmt, "continue {}", count),
+            Error::NoMatch(pattern) => write!(fmt, "no match: {}", pattern),
+            Error::Expansion(message) => write!(fmt, "{}", message),
+            Error::Io(err) => write!(fmt, "{}", describe_io_error(err)),
+            Error::ReadlineError(err) => write!(fmt, "{}", err),
+        }
 
     }
 
@@ -999,16 +3159,37 @@ impl core::fmt::Display for Error {
 impl std::error::Error for Error {}
 
 #[cfg(test)]
-
 mod tests {
 
     use super::*;
+    use parser::HereDocument;
+
+    fn tokenize(input: &str) -> Vec<String> {
+        lexer::tokenize(input)
+            .unwrap()
+            .0
+            .iter()
+            .map(|token| token.kind.to_string())
+            .collect()
+    }
+
+    fn parse(input: &str) -> ShellExec {
+        parser::parse(input).unwrap()
+    }
+
+    fn tokenize_and_expand(input: &str) -> Vec<String> {
+        let mut shell = Shell::default();
+        tokenize(input)
+            .iter()
+            .flat_map(|token| expand_word(token, &mut shell).unwrap())
+            .collect()
+    }
 
     #[test]
 
     fn tokenize_should_split_on_whitespace() {
 
-        let result = tokenize("echo foo     bar asd");
+        let result = tokenize_and_expand("echo foo     bar asd");
 
         assert_eq!(result, vec!["echo", "foo", "bar", "asd"]);
 
@@ -1046,7 +3227,7 @@ mod tests {
 
         for (test_case, expected_result) in test_cases {
 
-            assert_eq!(tokenize(test_case), expected_result);
+            assert_eq!(tokenize_and_expand(test_case), expected_result);
 
         }
 
@@ -1150,7 +3331,7 @@ mod tests {
 
         for (test_case, expected_result) in test_cases {
 
-            assert_eq!(tokenize(test_case), expected_result);
+            assert_eq!(tokenize_and_expand(test_case), expected_result);
 
         }
 
@@ -1190,7 +3371,7 @@ mod tests {
 
         for (test_case, expected_result) in test_cases {
 
-            assert_eq!(tokenize(test_case), expected_result);
+            assert_eq!(tokenize_and_expand(test_case), expected_result);
 
         }
 
@@ -1208,11 +3389,11 @@ mod tests {
 
                 "ls /tmp/baz > /tmp/foo/baz.md",
 
-                ShellExec::RedirectedStdOut(
+                ShellExec::Redirected(
 
-                    Command::SysProgram(String::from("ls"), vec![String::from("/tmp/baz")]),
+                    vec![String::from("ls"), String::from("/tmp/baz")],
 
-                    PathBuf::from("/tmp/foo/baz.md"),
+                    vec![Redirection::Output(1, String::from("/tmp/foo/baz.md"))],
 
                 ),
 
@@ -1222,11 +3403,11 @@ mod tests {
 
                 "ls /tmp/baz 1> /tmp/foo/baz.md",
 
-                ShellExec::RedirectedStdOut(
+                ShellExec::Redirected(
 
-                    Command::SysProgram(String::from("ls"), vec![String::from("/tmp/baz")]),
+                    vec![String::from("ls"), String::from("/tmp/baz")],
 
-                    PathBuf::from("/tmp/foo/baz.md"),
+                    vec![Redirection::Output(1, String::from("/tmp/foo/baz.md"))],
 
                 ),
 
@@ -1252,11 +3433,11 @@ mod tests {
 
             "ls /tmp/baz 2> /tmp/foo/baz.md",
 
-            ShellExec::RedirectedStdErr(
+            ShellExec::Redirected(
 
-                Command::SysProgram(String::from("ls"), vec![String::from("/tmp/baz")]),
+                vec![String::from("ls"), String::from("/tmp/baz")],
 
-                PathBuf::from("/tmp/foo/baz.md"),
+                vec![Redirection::Output(2, String::from("/tmp/foo/baz.md"))],
 
             ),
 
@@ -1282,11 +3463,11 @@ mod tests {
 
                 "ls /tmp/baz >> /tmp/foo/baz.md",
 
-                ShellExec::RedirectedStdOutAppend(
+                ShellExec::Redirected(
 
-                    Command::SysProgram(String::from("ls"), vec![String::from("/tmp/baz")]),
+                    vec![String::from("ls"), String::from("/tmp/baz")],
 
-                    PathBuf::from("/tmp/foo/baz.md"),
+                    vec![Redirection::Append(1, String::from("/tmp/foo/baz.md"))],
 
                 ),
 
@@ -1296,11 +3477,11 @@ mod tests {
 
                 "ls /tmp/baz 1>> /tmp/foo/baz.md",
 
-                ShellExec::RedirectedStdOutAppend(
+                ShellExec::Redirected(
 
-                    Command::SysProgram(String::from("ls"), vec![String::from("/tmp/baz")]),
+                    vec![String::from("ls"), String::from("/tmp/baz")],
 
-                    PathBuf::from("/tmp/foo/baz.md"),
+                    vec![Redirection::Append(1, String::from("/tmp/foo/baz.md"))],
 
                 ),
 
@@ -1326,11 +3507,11 @@ mod tests {
 
             "ls /tmp/baz 2>> /tmp/foo/baz.md",
 
-            ShellExec::RedirectedStdErrAppend(
+            ShellExec::Redirected(
 
-                Command::SysProgram(String::from("ls"), vec![String::from("/tmp/baz")]),
+                vec![String::from("ls"), String::from("/tmp/baz")],
 
-                PathBuf::from("/tmp/foo/baz.md"),
+                vec![Redirection::Append(2, String::from("/tmp/foo/baz.md"))],
 
             ),
 
@@ -1344,4 +3525,660 @@ mod tests {
 
     }
 
-}
\ No newline at end of file
+
+    #[test]
+    fn tokenize_should_split_unquoted_pipe_into_separate_token() {
+        let test_cases = vec![
+            ("ls | grep foo", vec!["ls", "|", "grep", "foo"]),
+            ("ls|grep foo|wc", vec!["ls", "|", "grep", "foo", "|", "wc"]),
+            (r#"echo 'a|b' "c|d" e\|f"#, vec!["echo", "a|b", "c|d", "e|f"]),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(tokenize_and_expand(test_case), expected_result);
+        }
+    }
+
+    #[test]
+    fn parse_into_command_should_return_pipeline_in_case_tokens_contain_pipe_operator() {
+        let test_cases = vec![
+            (
+                "ls /tmp | grep foo > /tmp/out.md",
+                ShellExec::Pipeline(vec![
+                    ShellExec::PrintToStd(vec![String::from("ls"), String::from("/tmp")]),
+                    ShellExec::Redirected(
+                        vec![String::from("grep"), String::from("foo")],
+                        vec![Redirection::Output(1, String::from("/tmp/out.md"))],
+                    ),
+                ]),
+            ),
+            (
+                "echo foo | cat | wc",
+                ShellExec::Pipeline(vec![
+                    ShellExec::PrintToStd(vec![String::from("echo"), String::from("foo")]),
+                    ShellExec::PrintToStd(vec![String::from("cat")]),
+                    ShellExec::PrintToStd(vec![String::from("wc")]),
+                ]),
+            ),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(parse(test_case), expected_result);
+        }
+        for test_case in ["ls |", "| wc"] {
+            assert!(parser::parse(test_case).is_err());
+        }
+    }
+
+    #[test]
+    fn expand_word_should_replace_last_status_outside_single_quotes() {
+        let mut shell = Shell {
+            last_status: 127,
+            ..Default::default()
+        };
+        let test_cases = vec![
+            ("$?", vec!["127"]),
+            (r#""status: $?""#, vec!["status: 127"]),
+            ("'$?'", vec!["$?"]),
+            (r#"\$?"#, vec!["$?"]),
+            (r#""\$?""#, vec!["$?"]),
+            ("$", vec!["$"]),
+            ("${?}", vec!["127"]),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(expand_word(test_case, &mut shell).unwrap(), expected_result);
+        }
+    }
+
+    #[test]
+    fn expand_word_should_substitute_variables_and_split_unquoted_results() {
+        let mut shell = Shell {
+            variables: HashMap::from([
+                (String::from("FOO"), String::from("foo")),
+                (String::from("LIST"), String::from("  a b\tc  ")),
+                (String::from("EMPTY"), String::new()),
+            ]),
+            ..Default::default()
+        };
+        let test_cases = vec![
+            ("$FOO", vec!["foo"]),
+            ("${FOO}bar", vec!["foobar"]),
+            ("$FOObar", vec![]),
+            (r#""$FOO $UNSET""#, vec!["foo "]),
+            ("'$FOO'", vec!["$FOO"]),
+            (r#"\$FOO"#, vec!["$FOO"]),
+            ("x${LIST}y", vec!["x", "a", "b", "c", "y"]),
+            (r#""$LIST""#, vec!["  a b\tc  "]),
+            ("$EMPTY", vec![]),
+            (r#""$EMPTY""#, vec![""]),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(expand_word(test_case, &mut shell).unwrap(), expected_result);
+        }
+    }
+
+    #[test]
+    fn expand_word_should_give_quoted_at_a_field_per_positional_parameter() {
+        let mut shell = Shell {
+            variables: HashMap::from([(String::from("IFS"), String::from(":"))]),
+            positional: vec![String::from("a b"), String::new(), String::from("c")],
+            ..Default::default()
+        };
+        let test_cases = vec![
+            (r#""$@""#, vec!["a b", "", "c"]),
+            (r#"x"$@"y"#, vec!["xa b", "", "cy"]),
+            ("$@", vec!["a b", "c"]),
+            (r#""$*""#, vec!["a b::c"]),
+            ("$#", vec!["3"]),
+            ("${2}$1", vec!["a b"]),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(expand_word(test_case, &mut shell).unwrap(), expected_result);
+        }
+        shell.positional.clear();
+        assert_eq!(expand_word(r#""$@""#, &mut shell).unwrap(), Vec::<String>::new());
+        assert_eq!(expand_word(r#"x"$@""#, &mut shell).unwrap(), vec!["x"]);
+    }
+
+    #[test]
+    fn expand_word_should_apply_parameter_expansion_operators() {
+        let mut shell = Shell {
+            variables: HashMap::from([
+                (String::from("FILE"), String::from("src/main.tar.gz")),
+                (String::from("EMPTY"), String::new()),
+                (String::from("STAR"), String::from("a*b")),
+                (String::from("X"), String::from("abc")),
+            ]),
+            ..Default::default()
+        };
+        let test_cases = vec![
+            ("${UNSET:-a b}", vec!["a", "b"]),
+            ("${UNSET:-'a  b'}c", vec!["a  bc"]),
+            ("${UNSET:-\"\"}", vec![""]),
+            (r#""${UNSET:-a b}""#, vec!["a b"]),
+            ("${EMPTY:-x}", vec!["x"]),
+            ("${EMPTY-x}", vec![]),
+            ("${FILE:+set}", vec!["set"]),
+            ("${UNSET:+set}", vec![]),
+            ("${#FILE}", vec!["15"]),
+            ("${FILE#*.}", vec!["tar.gz"]),
+            ("${FILE##*.}", vec!["gz"]),
+            ("${FILE%.*}", vec!["src/main.tar"]),
+            ("${FILE%%.*}", vec!["src/main"]),
+            ("${FILE#'src'}", vec!["/main.tar.gz"]),
+            ("${STAR#'a*'}", vec!["b"]),
+            ("${FILE/a/A}", vec!["src/mAin.tar.gz"]),
+            ("${FILE//a/A}", vec!["src/mAin.tAr.gz"]),
+            ("${FILE/#src/lib}", vec!["lib/main.tar.gz"]),
+            ("${FILE/%gz/xz}", vec!["src/main.tar.xz"]),
+            ("${X/#/P}", vec!["Pabc"]),
+            ("${X/%/S}", vec!["abcS"]),
+            ("${X///R}", vec!["abc"]),
+            ("${FILE:4:4}", vec!["main"]),
+            ("${FILE: -2}", vec!["gz"]),
+            ("${FILE:4:-7}", vec!["main"]),
+            ("${UNSET:=assigned}$UNSET", vec!["assignedassigned"]),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(expand_word(test_case, &mut shell).unwrap(), expected_result);
+        }
+        assert!(matches!(expand_word("${1:=x}", &mut shell), Err(Error::Expansion(_))));
+        for test_case in ["${:-a}", "${é}", "${X:}", "${X!}", "${@:}", "${1x}"] {
+            match expand_word(test_case, &mut shell) {
+                Err(Error::Expansion(message)) => {
+                    assert_eq!(message, format!("{}: bad substitution", test_case))
+                }
+                result => panic!("{}: {:?}", test_case, result),
+            }
+        }
+        shell.name = String::from("sh");
+        shell.positional = vec![String::from("one"), String::from("two"), String::from("three")];
+        let test_cases = vec![
+            ("${@:2}", vec!["two", "three"]),
+            (r#""${@:2}""#, vec!["two", "three"]),
+            (r#"x"${@:2:1}"y"#, vec!["xtwoy"]),
+            ("${@:0:1}", vec!["sh"]),
+            ("${@: -1}", vec!["three"]),
+            ("${*:1:2}", vec!["one", "two"]),
+            (r#""${*:1:2}""#, vec!["one two"]),
+            ("${@:4}", vec![]),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(expand_word(test_case, &mut shell).unwrap(), expected_result);
+        }
+        assert!(matches!(expand_word("${@:1:-1}", &mut shell), Err(Error::Expansion(_))));
+    }
+
+    #[test]
+    fn expand_word_should_evaluate_arithmetic_expansions() {
+        let mut shell = Shell {
+            variables: HashMap::from([(String::from("N"), String::from("4"))]),
+            ..Default::default()
+        };
+        let test_cases = vec![
+            ("$((1 + 2 * 3))", vec!["7"]),
+            ("$(( (N + 1) * 2 ))", vec!["10"]),
+            (r#""$(($N ** 2))""#, vec!["16"]),
+            ("$((N += 1))$N", vec!["55"]),
+            ("x$((-1))y", vec!["x-1y"]),
+            ("${N:$((1 - 1)):1}", vec!["5"]),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(expand_word(test_case, &mut shell).unwrap(), expected_result);
+        }
+        assert!(matches!(expand_word("$((1 / 0))", &mut shell), Err(Error::Exit(1))));
+        shell.interactive = true;
+        assert!(matches!(expand_word("$((1 / 0))", &mut shell), Err(Error::Expansion(_))));
+        assert_eq!(arithmetic_expression("(1 + 2)"), Some("1 + 2"));
+        assert_eq!(arithmetic_expression("(a) | (b)"), None);
+    }
+
+    #[test]
+    fn command_substitution_should_give_here_documents_their_bodies() {
+        let mut shell = Shell {
+            variables: HashMap::from([(String::from("X"), String::from("x"))]),
+            ..Default::default()
+        };
+        let test_cases = vec![
+            ("$(read -r l <<E\na $X\nE\necho $l)", vec!["a", "x"]),
+            ("\"$(read -r l <<'E'\na  $X\nE\necho \"$l\")\"", vec!["a  $X"]),
+            ("$(read -r l <<-E\n\ta\n\tE\necho $l)b", vec!["ab"]),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(expand_word(test_case, &mut shell).unwrap(), expected_result);
+        }
+    }
+
+    #[test]
+    fn assignment_should_split_words_with_valid_names_only() {
+        let test_cases = vec![
+            ("FOO=bar", Some(("FOO", "bar"))),
+            ("_x1='a b'", Some(("_x1", "'a b'"))),
+            ("EMPTY=", Some(("EMPTY", ""))),
+            ("1X=bar", None),
+            ("=bar", None),
+            ("'FOO'=bar", None),
+            ("echo", None),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(assignment(test_case), expected_result);
+        }
+    }
+
+    #[test]
+    fn tokenize_should_split_unquoted_list_operators_into_separate_tokens() {
+        let test_cases = vec![
+            (
+                "make && ./run || echo failed; pwd",
+                vec!["make", "&&", "./run", "||", "echo", "failed", ";", "pwd"],
+            ),
+            ("a&&b||c;d", vec!["a", "&&", "b", "||", "c", ";", "d"]),
+            (r#"echo 'a;b' "c&&d" e\;f"#, vec!["echo", "a;b", "c&&d", "e;f"]),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(tokenize_and_expand(test_case), expected_result);
+        }
+    }
+
+    #[test]
+    fn parse_into_command_should_return_list_and_and_or_nodes_in_case_tokens_contain_list_operators(
+    ) {
+        let command = |word: &str| ShellExec::PrintToStd(vec![String::from(word)]);
+        let test_cases = vec![
+            (
+                "make && ./run || echo; pwd;",
+                ShellExec::List(vec![
+                    ShellExec::Or(
+                        Box::new(ShellExec::And(
+                            Box::new(command("make")),
+                            Box::new(command("./run")),
+                        )),
+                        Box::new(command("echo")),
+                    ),
+                    command("pwd"),
+                ]),
+            ),
+            (
+                "true || ls | wc",
+                ShellExec::Or(
+                    Box::new(command("true")),
+                    Box::new(ShellExec::Pipeline(vec![command("ls"), command("wc")])),
+                ),
+            ),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(parse(test_case), expected_result);
+        }
+        for test_case in ["; pwd", "make &&", "|| pwd", "pwd;; pwd"] {
+            assert!(parser::parse(test_case).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_into_command_should_return_input_redirections_in_case_tokens_contain_less_than_operators(
+    ) {
+        let words = |words: &[&str]| words.iter().map(|word| word.to_string()).collect();
+        let test_cases = vec![
+            (
+                "wc -l<file.txt",
+                ShellExec::Redirected(
+                    words(&["wc", "-l"]),
+                    vec![Redirection::Input(0, String::from("file.txt"))],
+                ),
+            ),
+            (
+                "cat <<EOF",
+                ShellExec::Redirected(
+                    words(&["cat"]),
+                    vec![Redirection::HereDocument(
+                        0,
+                        HereDocument {
+                            delimiter: String::from("EOF"),
+                            expand: true,
+                            ..Default::default()
+                        },
+                    )],
+                ),
+            ),
+            (
+                "cat <<- 'E'O\\F",
+                ShellExec::Redirected(
+                    words(&["cat"]),
+                    vec![Redirection::HereDocument(
+                        0,
+                        HereDocument {
+                            delimiter: String::from("EOF"),
+                            strip_tabs: true,
+                            ..Default::default()
+                        },
+                    )],
+                ),
+            ),
+            (
+                "tr a b <<< \"$X y\"",
+                ShellExec::Redirected(
+                    words(&["tr", "a", "b"]),
+                    vec![Redirection::HereString(0, String::from("\"$X y\""))],
+                ),
+            ),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(parse(test_case), expected_result);
+        }
+    }
+
+    #[test]
+    fn parse_into_command_should_return_every_redirection_in_order_with_its_descriptor() {
+        let words = |words: &[&str]| words.iter().map(|word| word.to_string()).collect();
+        let file = |file: &str| String::from(file);
+        let test_cases = vec![
+            (
+                "cmd > out 2>&1",
+                ShellExec::Redirected(
+                    words(&["cmd"]),
+                    vec![Redirection::Output(1, file("out")), Redirection::Duplicate(2, 1)],
+                ),
+            ),
+            (
+                "cmd 2>&1 >out",
+                ShellExec::Redirected(
+                    words(&["cmd"]),
+                    vec![Redirection::Duplicate(2, 1), Redirection::Output(1, file("out"))],
+                ),
+            ),
+            (
+                "cmd &>>log 3<>rw 4>&- >&2",
+                ShellExec::Redirected(
+                    words(&["cmd"]),
+                    vec![
+                        Redirection::Append(1, file("log")),
+                        Redirection::Duplicate(2, 1),
+                        Redirection::ReadWrite(3, file("rw")),
+                        Redirection::Close(4),
+                        Redirection::Duplicate(1, 2),
+                    ],
+                ),
+            ),
+            (
+                "cmd 0<&3 >|out",
+                ShellExec::Redirected(
+                    words(&["cmd"]),
+                    vec![Redirection::Duplicate(0, 3), Redirection::Output(1, file("out"))],
+                ),
+            ),
+            (
+                "> out echo a 2> err b",
+                ShellExec::Redirected(
+                    words(&["echo", "a", "b"]),
+                    vec![Redirection::Output(1, file("out")), Redirection::Output(2, file("err"))],
+                ),
+            ),
+            (
+                "X=1 <in cmd",
+                ShellExec::Redirected(
+                    words(&["X=1", "cmd"]),
+                    vec![Redirection::Input(0, file("in"))],
+                ),
+            ),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(parse(test_case), expected_result);
+        }
+        for test_case in ["cmd >", "cmd > >out", "cmd <&file"] {
+            assert!(parser::parse(test_case).is_err());
+        }
+    }
+
+    #[test]
+    fn tokenize_should_split_redirection_operators_and_keep_their_descriptor() {
+        let test_cases = vec![
+            ("cmd>out 2>&1", vec!["cmd", ">", "out", "2", ">&", "1"]),
+            ("cmd a2>x 12>y", vec!["cmd", "a2", ">", "x", "12", ">", "y"]),
+            ("cmd &>x <>y 3>|z", vec!["cmd", "&>", "x", "<>", "y", "3", ">|", "z"]),
+            ("echo '>' \"2>\" \\>", vec!["echo", "'>'", "\"2>\"", "\\>"]),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(tokenize(test_case), expected_result);
+        }
+    }
+
+    #[test]
+    fn expand_here_document_should_expand_parameters_but_keep_quotes() {
+        let mut shell = Shell {
+            variables: HashMap::from([(String::from("X"), String::from("x"))]),
+            ..Default::default()
+        };
+        let test_cases = vec![
+            ("$X '$X' \"$X\"\n", "x 'x' \"x\"\n"),
+            ("\\$X \\\\ \\a\n", "$X \\ \\a\n"),
+            ("a\\\nb\n", "ab\n"),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(expand_here_document(test_case, &mut shell).unwrap(), expected_result);
+        }
+    }
+
+    #[test]
+    fn command_status_should_report_errors_as_failure_but_keep_unwinding_on_exit() {
+        let not_found = io::Error::from_raw_os_error(libc::ENOENT);
+        assert_eq!(command_status(Err(Error::Io(not_found))).ok(), Some(1));
+        assert_eq!(command_status(Ok(3)).ok(), Some(3));
+        assert!(matches!(command_status(Err(Error::Exit(4))), Err(Error::Exit(4))));
+        assert_eq!(
+            Error::Io(io::Error::from_raw_os_error(libc::EACCES)).to_string(),
+            "Permission denied"
+        );
+    }
+
+    #[test]
+    fn external_program_output_should_reach_redirect_targets_byte_for_byte() {
+        let dir = env::temp_dir().join(format!("binary-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let bytes = [b' ', 0xff, 0, 0xfe, b'\n', b'\t', b' ', b'\n', b'\n'];
+        fs::write(dir.join("in"), bytes).unwrap();
+        let dir = dir.display();
+        let mut shell = Shell::default();
+        let script = format!("cat {0}/in > {0}/copy; cat < {0}/in | cat | cat > {0}/piped", dir);
+        assert_eq!(run_script(&script, &mut shell).unwrap(), 0);
+        assert_eq!(fs::read(format!("{}/copy", dir)).unwrap(), bytes);
+        assert_eq!(fs::read(format!("{}/piped", dir)).unwrap(), bytes);
+        fs::remove_dir_all(dir.to_string()).unwrap();
+    }
+
+    #[test]
+    fn err_trap_should_run_for_failures_outside_conditions_and_keep_the_status() {
+        let mut shell = Shell::default();
+        run_script("trap 'FIRED=$((FIRED + 1)) STATUS=$?' ERR", &mut shell).unwrap();
+        // `((0))` fails and `((1))` succeeds.
+        let test_cases = vec![
+            ("((0))", "1"),
+            ("((0)); ((0))", "2"),
+            ("((0)) && ((1))", "0"),
+            ("((1)) && ((0))", "1"),
+            ("((0)) || ((0))", "1"),
+            ("if ((0)); then ((1)); fi", "0"),
+            ("while ((0)); do ((1)); done", "0"),
+            ("if ((1)); then ((0)); fi", "1"),
+        ];
+        for (test_case, expected_result) in test_cases {
+            shell.variables.insert(String::from("FIRED"), String::from("0"));
+            run_script(test_case, &mut shell).unwrap();
+            let fired = shell.variables.get("FIRED").map(String::as_str);
+            assert_eq!(fired, Some(expected_result), "{:?}", test_case);
+        }
+        assert_eq!(run_script("((0)); LAST=$?", &mut shell).unwrap(), 0);
+        assert_eq!(shell.variables.get("STATUS").map(String::as_str), Some("1"));
+        assert_eq!(shell.variables.get("LAST").map(String::as_str), Some("1"));
+    }
+
+    #[test]
+    fn exit_trap_should_run_with_the_last_status_and_trap_p_should_list_traps() {
+        let dir = env::temp_dir().join(format!("trap-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.display();
+        let mut shell = Shell::default();
+        run_script(r#"trap 'EXITED=$?' EXIT; trap "echo 'a b'" ERR"#, &mut shell).unwrap();
+        let script = format!("trap -p > {0}/all; trap -p ERR > {0}/err", dir);
+        assert_eq!(run_script(&script, &mut shell).unwrap(), 0);
+        let err = r#"trap -- 'echo '\''a b'\''' ERR"#;
+        assert_eq!(
+            fs::read_to_string(format!("{}/all", dir)).unwrap(),
+            format!("trap -- 'EXITED=$?' EXIT\n{}\n", err)
+        );
+        assert_eq!(fs::read_to_string(format!("{}/err", dir)).unwrap(), format!("{}\n", err));
+        run_script("trap - ERR; ((0))", &mut shell).unwrap();
+        run_trap(Condition::Exit, &mut shell).unwrap();
+        assert_eq!(shell.variables.get("EXITED").map(String::as_str), Some("1"));
+        fs::remove_dir_all(dir.to_string()).unwrap();
+    }
+
+    #[test]
+    fn export_and_unset_should_control_which_variables_reach_the_environment() {
+        let mut shell = Shell {
+            variables: HashMap::from([(String::from("LOCAL"), String::from("1"))]),
+            ..Default::default()
+        };
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(shell.environment(), vec![]);
+
+        assert_eq!(export(&args(&["LOCAL", "NEW=a \"b\""]), &mut shell), CommandOutput::Noop);
+        let mut environment = shell.environment();
+        environment.sort();
+        assert_eq!(
+            environment,
+            vec![
+                (String::from("LOCAL"), String::from("1")),
+                (String::from("NEW"), String::from("a \"b\"")),
+            ]
+        );
+        assert_eq!(
+            export(&args(&["-p"]), &mut shell),
+            CommandOutput::StdOut(String::from("export LOCAL=\"1\"\nexport NEW=\"a \\\"b\\\"\""))
+        );
+
+        assert_eq!(unset(&args(&["LOCAL"]), &mut shell), CommandOutput::Noop);
+        assert_eq!(shell.parameter("LOCAL"), None);
+        assert_eq!(
+            shell.environment(),
+            vec![(String::from("NEW"), String::from("a \"b\""))]
+        );
+        assert_eq!(
+            export(&args(&["1X=2"]), &mut shell),
+            CommandOutput::StdErr(String::from("export: `1X=2': not a valid identifier"), 1)
+        );
+    }
+
+    #[test]
+    fn assignments_should_be_made_from_left_to_right() {
+        let mut shell = Shell::default();
+        let words: Vec<String> = ["x=1", "y=$x", "x=2"].iter().map(|w| w.to_string()).collect();
+        let status = run_simple_command(&words, RedirectedFds::default(), &mut shell, false);
+        assert_eq!(status.unwrap(), 0);
+        assert_eq!(shell.parameter("x").as_deref(), Some("2"));
+        assert_eq!(shell.parameter("y").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn path_should_follow_the_path_variable_and_fall_back_to_default_search_path() {
+        let mut shell = Shell::default();
+        assert_eq!(shell.path(), DEFAULT_PATH);
+        shell
+            .variables
+            .insert(String::from("PATH"), String::from("/opt/bin:/usr/bin"));
+        assert_eq!(shell.path(), "/opt/bin:/usr/bin");
+    }
+
+    #[test]
+    fn matches_pattern_should_support_wildcards_bracket_expressions_and_escapes() {
+        let test_cases = vec![
+            ("*.rs", "main.rs", true),
+            ("*.rs", "main.rs.bak", false),
+            ("m??n.rs", "main.rs", true),
+            ("[a-c]x", "bx", true),
+            ("[!a-c]x", "bx", false),
+            ("[]]x", "]x", true),
+            ("[x", "[x", true),
+            (r"\*", "*", true),
+            (r"\*", "a", false),
+            ("*a*b", "xaxxb", true),
+        ];
+        for (pattern, name, expected_result) in test_cases {
+            let pattern: Vec<char> = pattern.chars().collect();
+            let name: Vec<char> = name.chars().collect();
+            assert_eq!(matches_pattern(&pattern, &name), expected_result);
+        }
+    }
+
+    #[test]
+    fn expand_word_should_replace_unquoted_patterns_with_sorted_file_names() {
+        let dir = env::temp_dir().join(format!("glob-test-{}", process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in ["b.rs", "a.rs", ".hidden.rs", "*.rs", "sub/c.rs"] {
+            File::create(dir.join(name)).unwrap();
+        }
+        let dir = dir.display();
+        let mut shell = Shell::default();
+        let test_cases = vec![
+            (format!("{}/*.rs", dir), vec![format!("{0}/*.rs {0}/a.rs {0}/b.rs", dir)]),
+            (format!("{}/.*.rs", dir), vec![format!("{}/.hidden.rs", dir)]),
+            (format!("{}/*/*.rs", dir), vec![format!("{}/sub/c.rs", dir)]),
+            (format!("'{}/*.rs'", dir), vec![format!("{}/*.rs", dir)]),
+            (format!(r"{}/\*.rs", dir), vec![format!("{}/*.rs", dir)]),
+            (format!("{}/*.txt", dir), vec![format!("{}/*.txt", dir)]),
+        ];
+        for (test_case, expected_result) in test_cases {
+            let fields = expand_word(&test_case, &mut shell).unwrap();
+            assert_eq!(vec![fields.join(" ")], expected_result);
+        }
+        shell.options.nullglob = true;
+        assert!(expand_word(&format!("{}/*.txt", dir), &mut shell).unwrap().is_empty());
+        shell.options.failglob = true;
+        assert!(matches!(
+            expand_word(&format!("{}/*.txt", dir), &mut shell),
+            Err(Error::NoMatch(_))
+        ));
+        fs::remove_dir_all(dir.to_string()).unwrap();
+    }
+
+    #[test]
+    fn tokenize_should_keep_command_substitutions_in_a_single_word() {
+        let test_cases = vec![
+            ("echo $(echo a | wc -c; pwd)", vec!["echo", "$(echo a | wc -c; pwd)"]),
+            ("x=$(echo $(echo ')'))y z", vec!["x=$(echo $(echo ')'))y", "z"]),
+            ("echo `echo a && b` c", vec!["echo", "`echo a && b`", "c"]),
+            ("echo '$(a b)'", vec!["echo", "'$(a b)'"]),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(tokenize(test_case), expected_result);
+        }
+    }
+
+    #[test]
+    fn builtin_commands_should_list_every_command_the_shell_runs_itself() {
+        for name in BUILTIN_COMMANDS {
+            let command = Command::from_args(vec![name.to_string()]);
+            assert!(!matches!(command, Command::SysProgram(..)), "{}", name);
+        }
+        let command = Command::from_args(vec![String::from("ls")]);
+        assert!(matches!(command, Command::SysProgram(..)));
+    }
+
+    #[test]
+    fn run_in_foreground_should_keep_stopped_jobs_and_forget_finished_ones() {
+        let mut shell = Shell::default();
+        let spawn = |program: &str, args: &[&str]| {
+            std::process::Command::new(program).args(args).spawn().unwrap().id() as libc::pid_t
+        };
+        let finished = Job::new(vec![spawn("true", &[])], None, String::from("true"));
+        assert_eq!(run_in_foreground(finished, &mut shell).unwrap(), 0);
+        assert_eq!(shell.jobs.iter().count(), 0);
+
+        let pid = spawn("sleep", &["5"]);
+        unsafe { libc::kill(pid, libc::SIGSTOP) };
+        let stopped = Job::new(vec![pid], None, String::from("sleep 5"));
+        assert_eq!(run_in_foreground(stopped, &mut shell).unwrap(), 128 + libc::SIGTSTP);
+        let job = shell.jobs.get(shell.jobs.resolve(None).unwrap()).unwrap();
+        assert_eq!((job.pid, job.state, job.command.as_str()), (pid, JobState::Stopped, "sleep 5"));
+
+        unsafe { libc::kill(pid, libc::SIGKILL) };
+        wait_for(pid).unwrap();
+    }
+}
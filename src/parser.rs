@@ -0,0 +1,693 @@
+//! Builds the command tree for a command line by recursive descent over the
+//! POSIX shell grammar:
+//!
+//! ```text
+//! program        := compound_list
+//! compound_list  := linebreak (and_or (separator and_or)* separator?)?
+//! and_or         := pipeline (('&&' | '||') linebreak pipeline)*
+//! pipeline       := command ('|' linebreak command)*
+//! command        := (compound_command | ARITHMETIC) io_redirect* | simple_command
+//! compound_command := if_clause | while_clause | until_clause | for_clause
+//! if_clause      := 'if' compound_list 'then' compound_list
+//!                   ('elif' compound_list 'then' compound_list)*
+//!                   ('else' compound_list)? 'fi'
+//! while_clause   := 'while' compound_list do_group
+//! until_clause   := 'until' compound_list do_group
+//! for_clause     := 'for' NAME linebreak ('in' WORD* (';' | NEWLINE) | ';')? linebreak do_group
+//! do_group       := 'do' compound_list 'done'
+//! simple_command := (WORD | io_redirect)+
+//! io_redirect    := IO_NUMBER? redirection_operator WORD
+//! separator      := (';' | '&' | NEWLINE) linebreak
+//! ```
+//!
+//! Reserved words such as `if` and `done` are words that are only recognised
+//! where a command starts. Lists in compound commands end at the reserved word
+//! that comes after them, and can't be empty.
+
+use std::os::fd::RawFd;
+
+use crate::lexer::{self, is_name, remove_quotes, Operator, Span, SyntaxError, Token, TokenKind};
+
+/// A parsed command line. Commands are kept as the raw words typed by the user,
+/// quotes included, and are only expanded right before they run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShellExec {
+    PrintToStd(Vec<String>),
+    /// A command with redirections, which are applied in order.
+    Redirected(Vec<String>, Vec<Redirection>),
+    Pipeline(Vec<ShellExec>),
+    /// `left && right`: runs `right` only if `left` succeeded.
+    And(Box<ShellExec>, Box<ShellExec>),
+    /// `left || right`: runs `right` only if `left` failed.
+    Or(Box<ShellExec>, Box<ShellExec>),
+    /// Commands separated by `;`, run one after another.
+    List(Vec<ShellExec>),
+    /// `command &`: runs in the background as a job, described by the text of
+    /// the command.
+    Background(Box<ShellExec>, String),
+    /// `((expression))`: succeeds if the arithmetic expression is non-zero.
+    Arithmetic(String),
+    /// `if condition; then body; elif condition; then body; else body; fi`:
+    /// runs the body of the first condition that succeeds, or the `else` body.
+    If(Vec<(ShellExec, ShellExec)>, Option<Box<ShellExec>>),
+    /// `while condition; do body; done`
+    While(Box<ShellExec>, Box<ShellExec>),
+    /// `until condition; do body; done`: runs the body while the condition fails.
+    Until(Box<ShellExec>, Box<ShellExec>),
+    /// `for name in words; do body; done`: runs the body with the variable set
+    /// to each field of the words, or each positional parameter without `in`.
+    For(String, Option<Vec<String>>, Box<ShellExec>),
+    /// A compound command with redirections, which apply to all of it.
+    RedirectedCompound(Box<ShellExec>, Vec<Redirection>),
+}
+
+impl ShellExec {
+    /// The command line without its redirections, to name it in the job table.
+    pub fn text(&self) -> String {
+        let join = |commands: &[ShellExec], separator| {
+            commands.iter().map(ShellExec::text).collect::<Vec<_>>().join(separator)
+        };
+        match self {
+            ShellExec::PrintToStd(words) | ShellExec::Redirected(words, _) => words.join(" "),
+            ShellExec::Pipeline(commands) => join(commands, " | "),
+            ShellExec::List(commands) => join(commands, "; "),
+            ShellExec::And(left, right) => format!("{} && {}", left.text(), right.text()),
+            ShellExec::Or(left, right) => format!("{} || {}", left.text(), right.text()),
+            ShellExec::Background(_, text) => format!("{} &", text),
+            ShellExec::Arithmetic(expression) => format!("(({}))", expression),
+            ShellExec::If(branches, otherwise) => {
+                let mut text = String::new();
+                for (i, (condition, body)) in branches.iter().enumerate() {
+                    let keyword = if i == 0 { "if" } else { "; elif" };
+                    text += &format!("{} {}; then {}", keyword, condition.text(), body.text());
+                }
+                if let Some(body) = otherwise {
+                    text += &format!("; else {}", body.text());
+                }
+                text + "; fi"
+            }
+            ShellExec::While(condition, body) => {
+                format!("while {}; do {}; done", condition.text(), body.text())
+            }
+            ShellExec::Until(condition, body) => {
+                format!("until {}; do {}; done", condition.text(), body.text())
+            }
+            ShellExec::For(name, words, body) => match words {
+                Some(words) => {
+                    format!("for {} in {}; do {}; done", name, words.join(" "), body.text())
+                }
+                None => format!("for {}; do {}; done", name, body.text()),
+            },
+            ShellExec::RedirectedCompound(command, _) => command.text(),
+        }
+    }
+
+    /// Whether this is a compound command, whose failure is that of a command
+    /// inside it.
+    pub fn is_compound(&self) -> bool {
+        matches!(
+            self,
+            ShellExec::If(..)
+                | ShellExec::While(..)
+                | ShellExec::Until(..)
+                | ShellExec::For(..)
+                | ShellExec::RedirectedCompound(..)
+        )
+    }
+}
+
+/// A redirection of one of a command's file descriptors. File names and words
+/// are kept unexpanded until the command runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Redirection {
+    /// `N< file`
+    Input(RawFd, String),
+    /// `N> file`
+    Output(RawFd, String),
+    /// `N>> file`
+    Append(RawFd, String),
+    /// `N<> file`, opened for both reading and writing.
+    ReadWrite(RawFd, String),
+    /// `N>&M` or `N<&M`: makes N a copy of M.
+    Duplicate(RawFd, RawFd),
+    /// `N>&-` or `N<&-`
+    Close(RawFd),
+    /// `N<< DELIMITER`, with the lines after the command line as input.
+    HereDocument(RawFd, HereDocument),
+    /// `N<<< word`, with the expanded word and a newline as input.
+    HereString(RawFd, String),
+}
+
+/// A here-document. Its body is made of the lines after the command line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HereDocument {
+    /// The line that ends the body, with quotes removed.
+    pub delimiter: String,
+    /// Whether leading tabs are stripped from the lines, as with `<<-`.
+    pub strip_tabs: bool,
+    /// Whether parameters and command substitutions in the body are expanded,
+    /// which is the case unless part of the delimiter was quoted.
+    pub expand: bool,
+    pub body: String,
+}
+
+/// The reserved words that end a list, which can't start a command.
+const CLOSING_WORDS: [&str; 6] = ["then", "elif", "else", "fi", "do", "done"];
+
+pub fn parse(input: &str) -> Result<ShellExec, SyntaxError> {
+    let (tokens, bodies) = lexer::tokenize(input)?;
+    Parser {
+        input,
+        tokens,
+        bodies: bodies.into_iter(),
+        pos: 0,
+        end: input.len(),
+    }
+    .program()
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<Token>,
+    /// The bodies of the here-documents yet to be parsed, in order.
+    bodies: std::vec::IntoIter<String>,
+    pos: usize,
+    /// Length of the input, where a missing token is reported.
+    end: usize,
+}
+
+type ParseResult<T> = Result<T, SyntaxError>;
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.pos).map(|token| &token.kind)
+    }
+
+    fn peek_operator(&self) -> Option<Operator> {
+        match self.peek() {
+            Some(TokenKind::Operator(operator)) => Some(*operator),
+            _ => None,
+        }
+    }
+
+    fn advance(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek() == Some(&TokenKind::Newline) {
+            self.pos += 1;
+        }
+    }
+
+    /// An error for the token at the current position.
+    fn unexpected(&self) -> SyntaxError {
+        let (token, span) = match self.tokens.get(self.pos) {
+            Some(token) => (token.kind.to_string(), token.span),
+            None => (
+                String::from("newline"),
+                Span {
+                    start: self.end,
+                    end: self.end,
+                },
+            ),
+        };
+        SyntaxError {
+            message: format!("syntax error near unexpected token `{}'", token),
+            span,
+        }
+    }
+
+    /// Whether the current token is one of the given reserved words.
+    fn at_reserved(&self, words: &[&str]) -> bool {
+        matches!(self.peek(), Some(TokenKind::Word(word)) if words.contains(&word.as_str()))
+    }
+
+    fn expect_reserved(&mut self, word: &str) -> ParseResult<()> {
+        if !self.at_reserved(&[word]) {
+            return Err(self.unexpected());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn program(&mut self) -> ParseResult<ShellExec> {
+        self.compound_list(&[])
+    }
+
+    /// Parses and-or lists up to the end of the input or, inside a compound
+    /// command, up to one of the reserved words that end the list.
+    fn compound_list(&mut self, ends: &[&str]) -> ParseResult<ShellExec> {
+        let mut commands = Vec::new();
+        self.skip_newlines();
+        while let Some(start) = self.tokens.get(self.pos).map(|token| token.span.start) {
+            if self.at_reserved(ends) {
+                break;
+            }
+            let command = self.and_or()?;
+            match self.peek() {
+                Some(TokenKind::Operator(Operator::Background)) => {
+                    let end = self.tokens[self.pos - 1].span.end;
+                    let text = self.input[start..end].to_string();
+                    commands.push(ShellExec::Background(Box::new(command), text));
+                    self.pos += 1;
+                    self.skip_newlines();
+                }
+                Some(TokenKind::Operator(Operator::Semicolon) | TokenKind::Newline) => {
+                    commands.push(command);
+                    self.pos += 1;
+                    self.skip_newlines();
+                }
+                None => {
+                    commands.push(command);
+                    break;
+                }
+                // Like `fi` in `while c; do if c; then b; fi done`.
+                Some(_) if self.at_reserved(ends) => {
+                    commands.push(command);
+                    break;
+                }
+                Some(_) => return Err(self.unexpected()),
+            }
+        }
+        if commands.is_empty() && !ends.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(match commands.len() {
+            0 => ShellExec::PrintToStd(vec![]),
+            1 => commands.remove(0),
+            _ => ShellExec::List(commands),
+        })
+    }
+
+    /// Parses pipelines joined by `&&` and `||`. Both operators have the same
+    /// precedence and associate to the left.
+    fn and_or(&mut self) -> ParseResult<ShellExec> {
+        let mut left = self.pipeline()?;
+        while let Some(operator @ (Operator::AndIf | Operator::OrIf)) = self.peek_operator() {
+            self.pos += 1;
+            self.skip_newlines();
+            let right = Box::new(self.pipeline()?);
+            left = match operator {
+                Operator::AndIf => ShellExec::And(Box::new(left), right),
+                _ => ShellExec::Or(Box::new(left), right),
+            };
+        }
+        Ok(left)
+    }
+
+    fn pipeline(&mut self) -> ParseResult<ShellExec> {
+        let mut stages = vec![self.command()?];
+        while self.peek_operator() == Some(Operator::Pipe) {
+            self.pos += 1;
+            self.skip_newlines();
+            stages.push(self.command()?);
+        }
+        Ok(if stages.len() == 1 {
+            stages.remove(0)
+        } else {
+            ShellExec::Pipeline(stages)
+        })
+    }
+
+    /// Parses a simple command, or a compound command with the redirections
+    /// that apply to all of it.
+    fn command(&mut self) -> ParseResult<ShellExec> {
+        let command = match self.peek() {
+            Some(TokenKind::Arithmetic(expression)) => {
+                let command = ShellExec::Arithmetic(expression.clone());
+                self.pos += 1;
+                command
+            }
+            Some(TokenKind::Word(word)) => match word.as_str() {
+                "if" => self.if_clause()?,
+                "while" | "until" => self.while_clause()?,
+                "for" => self.for_clause()?,
+                _ if self.at_reserved(&CLOSING_WORDS) => return Err(self.unexpected()),
+                _ => return self.simple_command(),
+            },
+            _ => return self.simple_command(),
+        };
+        let mut redirections = Vec::new();
+        loop {
+            match self.peek() {
+                Some(TokenKind::IoNumber(_)) => redirections.extend(self.redirection()?),
+                Some(TokenKind::Operator(operator)) if operator.is_redirection() => {
+                    redirections.extend(self.redirection()?)
+                }
+                _ => break,
+            }
+        }
+        Ok(if redirections.is_empty() {
+            command
+        } else {
+            ShellExec::RedirectedCompound(Box::new(command), redirections)
+        })
+    }
+
+    fn if_clause(&mut self) -> ParseResult<ShellExec> {
+        let mut branches = Vec::new();
+        loop {
+            // `if` or `elif`
+            self.pos += 1;
+            let condition = self.compound_list(&["then"])?;
+            self.expect_reserved("then")?;
+            let body = self.compound_list(&["elif", "else", "fi"])?;
+            branches.push((condition, body));
+            if !self.at_reserved(&["elif"]) {
+                break;
+            }
+        }
+        let otherwise = if self.at_reserved(&["else"]) {
+            self.pos += 1;
+            Some(Box::new(self.compound_list(&["fi"])?))
+        } else {
+            None
+        };
+        self.expect_reserved("fi")?;
+        Ok(ShellExec::If(branches, otherwise))
+    }
+
+    fn while_clause(&mut self) -> ParseResult<ShellExec> {
+        let until = self.at_reserved(&["until"]);
+        self.pos += 1;
+        let condition = Box::new(self.compound_list(&["do"])?);
+        let body = Box::new(self.do_group()?);
+        Ok(if until {
+            ShellExec::Until(condition, body)
+        } else {
+            ShellExec::While(condition, body)
+        })
+    }
+
+    fn for_clause(&mut self) -> ParseResult<ShellExec> {
+        self.pos += 1;
+        let name = match self.tokens.get(self.pos) {
+            Some(Token {
+                kind: TokenKind::Word(name),
+                ..
+            }) if is_name(name) => name.clone(),
+            Some(Token {
+                kind: TokenKind::Word(name),
+                span,
+            }) => {
+                return Err(SyntaxError {
+                    message: format!("`{}': not a valid identifier", name),
+                    span: *span,
+                })
+            }
+            _ => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        self.skip_newlines();
+        let words = if self.at_reserved(&["in"]) {
+            self.pos += 1;
+            let mut words = Vec::new();
+            while let Some(TokenKind::Word(word)) = self.peek() {
+                words.push(word.clone());
+                self.pos += 1;
+            }
+            match self.peek() {
+                Some(TokenKind::Operator(Operator::Semicolon) | TokenKind::Newline) => {
+                    self.pos += 1
+                }
+                _ => return Err(self.unexpected()),
+            }
+            Some(words)
+        } else {
+            if self.peek_operator() == Some(Operator::Semicolon) {
+                self.pos += 1;
+            }
+            None
+        };
+        self.skip_newlines();
+        let body = self.do_group()?;
+        Ok(ShellExec::For(name, words, Box::new(body)))
+    }
+
+    fn do_group(&mut self) -> ParseResult<ShellExec> {
+        self.expect_reserved("do")?;
+        let body = self.compound_list(&["done"])?;
+        self.expect_reserved("done")?;
+        Ok(body)
+    }
+
+    /// Parses the words and redirections of a simple command. Redirections
+    /// may appear anywhere, even before the command name, and keep their order.
+    fn simple_command(&mut self) -> ParseResult<ShellExec> {
+        let mut words = Vec::new();
+        let mut redirections = Vec::new();
+        loop {
+            match self.peek() {
+                Some(TokenKind::Word(word)) => {
+                    words.push(word.clone());
+                    self.pos += 1;
+                }
+                Some(TokenKind::IoNumber(_)) => redirections.extend(self.redirection()?),
+                Some(TokenKind::Operator(operator)) if operator.is_redirection() => {
+                    redirections.extend(self.redirection()?)
+                }
+                _ => break,
+            }
+        }
+        if words.is_empty() && redirections.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(if redirections.is_empty() {
+            ShellExec::PrintToStd(words)
+        } else {
+            ShellExec::Redirected(words, redirections)
+        })
+    }
+
+    fn redirection(&mut self) -> ParseResult<Vec<Redirection>> {
+        let fd = match self.peek() {
+            Some(&TokenKind::IoNumber(fd)) => {
+                self.pos += 1;
+                Some(fd)
+            }
+            _ => None,
+        };
+        // The lexer only produces a descriptor number right before an operator.
+        let Some(operator) = self.peek_operator() else {
+            return Err(self.unexpected());
+        };
+        self.pos += 1;
+        let Some(TokenKind::Word(target)) = self.peek().cloned() else {
+            return Err(self.unexpected());
+        };
+        let span = self.advance().map(|token| token.span);
+        let mut redirections =
+            redirection(fd, operator, target.clone()).ok_or_else(|| SyntaxError {
+                message: format!("{}: ambiguous redirect", target),
+                span: span.unwrap_or(Span {
+                    start: self.end,
+                    end: self.end,
+                }),
+            })?;
+        for redirection in &mut redirections {
+            if let Redirection::HereDocument(_, document) = redirection {
+                document.body = self.bodies.next().unwrap_or_default();
+            }
+        }
+        Ok(redirections)
+    }
+}
+
+/// Builds the redirections for an operator and its target word, or `None` if
+/// the target doesn't fit the operator.
+fn redirection(fd: Option<RawFd>, operator: Operator, target: String) -> Option<Vec<Redirection>> {
+    let input = fd.unwrap_or(libc::STDIN_FILENO);
+    let output = fd.unwrap_or(libc::STDOUT_FILENO);
+    let redirections = match operator {
+        Operator::Less => vec![Redirection::Input(input, target)],
+        Operator::LessGreat => vec![Redirection::ReadWrite(input, target)],
+        Operator::Great | Operator::Clobber => vec![Redirection::Output(output, target)],
+        Operator::DoubleGreat => vec![Redirection::Append(output, target)],
+        // `&> file` sends both stdout and stderr to the file, and so does
+        // `>& file` when the file isn't a descriptor number.
+        Operator::AndGreat => vec![
+            Redirection::Output(libc::STDOUT_FILENO, target),
+            Redirection::Duplicate(libc::STDERR_FILENO, libc::STDOUT_FILENO),
+        ],
+        Operator::AndDoubleGreat => vec![
+            Redirection::Append(libc::STDOUT_FILENO, target),
+            Redirection::Duplicate(libc::STDERR_FILENO, libc::STDOUT_FILENO),
+        ],
+        Operator::LessAnd | Operator::GreatAnd => {
+            let fd = if operator == Operator::LessAnd { input } else { output };
+            if target == "-" {
+                vec![Redirection::Close(fd)]
+            } else if target.bytes().all(|b| b.is_ascii_digit()) {
+                vec![Redirection::Duplicate(fd, target.parse().ok()?)]
+            } else if operator == Operator::GreatAnd && fd == libc::STDOUT_FILENO {
+                return redirection(None, Operator::AndGreat, target);
+            } else {
+                return None;
+            }
+        }
+        Operator::DoubleLess | Operator::DoubleLessDash => vec![Redirection::HereDocument(
+            input,
+            here_document(operator, &target),
+        )],
+        Operator::TripleLess => vec![Redirection::HereString(input, target)],
+        Operator::Pipe
+        | Operator::OrIf
+        | Operator::AndIf
+        | Operator::Semicolon
+        | Operator::Background => return None,
+    };
+    Some(redirections)
+}
+
+/// A here-document started by `<<` or `<<-` and its delimiter word, without
+/// its body yet.
+fn here_document(operator: Operator, target: &str) -> HereDocument {
+    HereDocument {
+        strip_tabs: operator == Operator::DoubleLessDash,
+        expand: !target.contains(['\'', '"', '\\']),
+        delimiter: remove_quotes(target),
+        body: String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_should_point_syntax_errors_at_the_offending_token() {
+        let test_cases = vec![
+            ("echo >", "syntax error near unexpected token `newline'", 6),
+            ("ls | | wc", "syntax error near unexpected token `|'", 5),
+            ("pwd;; pwd", "syntax error near unexpected token `;'", 4),
+            ("cat < ; ls", "syntax error near unexpected token `;'", 6),
+            ("&& ls", "syntax error near unexpected token `&&'", 0),
+            ("cat <&file", "file: ambiguous redirect", 6),
+            ("cat 99999999999>file", "99999999999: bad file descriptor", 4),
+            ("if true; then fi", "syntax error near unexpected token `fi'", 14),
+            ("while; do ls; done", "syntax error near unexpected token `;'", 5),
+            ("for 1 in a; do ls; done", "`1': not a valid identifier", 4),
+            ("done", "syntax error near unexpected token `done'", 0),
+        ];
+        for (test_case, message, start) in test_cases {
+            let err = parse(test_case).unwrap_err();
+            assert_eq!((err.message.as_str(), err.span.start), (message, start));
+        }
+    }
+
+    #[test]
+    fn parse_should_honour_quoting_of_operator_characters() {
+        let words = |words: &[&str]| words.iter().map(|word| word.to_string()).collect();
+        let test_cases = vec![
+            ("echo \">\" foo", ShellExec::PrintToStd(words(&["echo", "\">\"", "foo"]))),
+            ("echo '|' \\;", ShellExec::PrintToStd(words(&["echo", "'|'", "\\;"]))),
+            (
+                "sleep 1 && echo a & echo b &",
+                ShellExec::List(vec![
+                    ShellExec::Background(
+                        Box::new(ShellExec::And(
+                            Box::new(ShellExec::PrintToStd(words(&["sleep", "1"]))),
+                            Box::new(ShellExec::PrintToStd(words(&["echo", "a"]))),
+                        )),
+                        String::from("sleep 1 && echo a"),
+                    ),
+                    ShellExec::Background(
+                        Box::new(ShellExec::PrintToStd(words(&["echo", "b"]))),
+                        String::from("echo b"),
+                    ),
+                ]),
+            ),
+            ("echo one \\\ntwo\n", ShellExec::PrintToStd(words(&["echo", "one", "two"]))),
+            (
+                "echo a\n\necho b\n",
+                ShellExec::List(vec![
+                    ShellExec::PrintToStd(words(&["echo", "a"])),
+                    ShellExec::PrintToStd(words(&["echo", "b"])),
+                ]),
+            ),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(parse(test_case), Ok(expected_result));
+        }
+    }
+
+    #[test]
+    fn parse_should_read_descriptor_numbers_of_any_length() {
+        let redirected =
+            |redirections| ShellExec::Redirected(vec![String::from("cmd")], redirections);
+        let test_cases = vec![
+            (
+                "cmd 12>file 3<&12",
+                redirected(vec![
+                    Redirection::Output(12, String::from("file")),
+                    Redirection::Duplicate(3, 12),
+                ]),
+            ),
+            ("cmd 10<&-", redirected(vec![Redirection::Close(10)])),
+            ("cmd 012>>log", redirected(vec![Redirection::Append(12, String::from("log"))])),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(parse(test_case), Ok(expected_result), "{:?}", test_case);
+        }
+    }
+
+    #[test]
+    fn parse_should_build_compound_commands_with_their_redirections() {
+        let command = |words: &[&str]| {
+            ShellExec::PrintToStd(words.iter().map(|word| word.to_string()).collect())
+        };
+        let test_cases = vec![
+            (
+                "if a; then b; elif c\nthen d; e; else f; fi",
+                ShellExec::If(
+                    vec![
+                        (command(&["a"]), command(&["b"])),
+                        (
+                            command(&["c"]),
+                            ShellExec::List(vec![command(&["d"]), command(&["e"])]),
+                        ),
+                    ],
+                    Some(Box::new(command(&["f"]))),
+                ),
+            ),
+            (
+                "while read l; do echo $l done; done < in | wc",
+                ShellExec::Pipeline(vec![
+                    ShellExec::RedirectedCompound(
+                        Box::new(ShellExec::While(
+                            Box::new(command(&["read", "l"])),
+                            Box::new(command(&["echo", "$l", "done"])),
+                        )),
+                        vec![Redirection::Input(0, String::from("in"))],
+                    ),
+                    command(&["wc"]),
+                ]),
+            ),
+            (
+                "until a; do if b; then break; fi done",
+                ShellExec::Until(
+                    Box::new(command(&["a"])),
+                    Box::new(ShellExec::If(vec![(command(&["b"]), command(&["break"]))], None)),
+                ),
+            ),
+            (
+                "for x in a 'b c'\ndo ls $x; done",
+                ShellExec::For(
+                    String::from("x"),
+                    Some(vec![String::from("a"), String::from("'b c'")]),
+                    Box::new(command(&["ls", "$x"])),
+                ),
+            ),
+            (
+                "for x do ls; done",
+                ShellExec::For(String::from("x"), None, Box::new(command(&["ls"]))),
+            ),
+        ];
+        for (test_case, expected_result) in test_cases {
+            assert_eq!(parse(test_case), Ok(expected_result), "{:?}", test_case);
+        }
+    }
+}
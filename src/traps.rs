@@ -0,0 +1,222 @@
+//! Traps: commands the shell runs when it receives a signal, when a command
+//! fails or when it exits, and the signal dispositions that go with them.
+
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use libc::c_int;
+
+/// Signals that arrived and whose trap hasn't run yet, one bit per signal.
+static PENDING: AtomicU64 = AtomicU64::new(0);
+/// Signals ignored with `trap ''`, one bit per signal. Kept outside of the
+/// shell so that a child can read it between fork and exec.
+static IGNORED: AtomicU64 = AtomicU64::new(0);
+
+/// Every signal a trap can name, in the order of their numbers.
+const SIGNALS: [(&str, c_int); 29] = [
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM),
+    ("PROF", libc::SIGPROF),
+    ("WINCH", libc::SIGWINCH),
+    ("IO", libc::SIGIO),
+    ("SYS", libc::SIGSYS),
+];
+
+/// What sets off a trap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Condition {
+    /// The shell exiting.
+    Exit,
+    Signal(c_int),
+    /// A command failing.
+    Err,
+}
+
+impl Condition {
+    /// Reads a condition the way `trap` takes it: `EXIT` or `0`, `ERR`, or a
+    /// signal by number or by name, with or without its `SIG` prefix.
+    pub fn parse(name: &str) -> Option<Condition> {
+        let upper = name.to_ascii_uppercase();
+        match upper.as_str() {
+            "EXIT" | "0" => return Some(Condition::Exit),
+            "ERR" => return Some(Condition::Err),
+            _ => (),
+        }
+        let signal = match upper.parse::<c_int>() {
+            Ok(number) => SIGNALS.iter().find(|(_, signal)| *signal == number),
+            Err(_) => {
+                let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+                SIGNALS.iter().find(|(signal_name, _)| *signal_name == name)
+            }
+        };
+        signal.map(|&(_, signal)| Condition::Signal(signal))
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Exit => f.write_str("EXIT"),
+            Condition::Err => f.write_str("ERR"),
+            Condition::Signal(signal) => match SIGNALS.iter().find(|(_, s)| s == signal) {
+                Some((name, _)) => write!(f, "SIG{}", name),
+                None => write!(f, "{}", signal),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Traps {
+    /// The command of each trap; an empty one ignores the signal.
+    commands: BTreeMap<Condition, String>,
+    /// Set while a trap runs, so that it doesn't set off traps itself.
+    pub running: bool,
+}
+
+impl Traps {
+    pub fn get(&self, condition: Condition) -> Option<&str> {
+        self.commands.get(&condition).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Condition, &str)> {
+        self.commands
+            .iter()
+            .map(|(&condition, command)| (condition, command.as_str()))
+    }
+
+    /// Sets the trap for a condition, or resets it with `None`, and changes
+    /// the disposition of its signal to match. `job_control` tells which
+    /// signals the shell itself ignores.
+    pub fn set(
+        &mut self,
+        condition: Condition,
+        command: Option<String>,
+        job_control: bool,
+    ) -> io::Result<()> {
+        if let Condition::Signal(signal) = condition {
+            let handler = match command.as_deref() {
+                None => shell_disposition(signal, job_control),
+                Some("") => libc::SIG_IGN,
+                Some(_) => record as extern "C" fn(c_int) as libc::sighandler_t,
+            };
+            if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+            let bit = 1 << signal;
+            if command.as_deref() == Some("") {
+                IGNORED.fetch_or(bit, Ordering::SeqCst);
+            } else {
+                IGNORED.fetch_and(!bit, Ordering::SeqCst);
+            }
+        }
+        match command {
+            Some(command) => self.commands.insert(condition, command),
+            None => self.commands.remove(&condition),
+        };
+        Ok(())
+    }
+
+    /// Resets the traps in a forked subshell, which doesn't inherit them.
+    /// Ignored signals stay ignored.
+    pub fn reset_for_subshell(&mut self) {
+        self.commands.retain(|condition, command| {
+            if let (Condition::Signal(signal), false) = (condition, command.is_empty()) {
+                unsafe { libc::signal(*signal, libc::SIG_DFL) };
+            }
+            command.is_empty()
+        });
+        PENDING.store(0, Ordering::SeqCst);
+    }
+}
+
+/// The disposition of a signal without a trap: the shell ignores `SIGPIPE` to
+/// see failed writes as errors, and with job control the signals that would
+/// stop it.
+fn shell_disposition(signal: c_int, job_control: bool) -> libc::sighandler_t {
+    let job_control_signal = crate::jobs::JOB_CONTROL_SIGNALS.contains(&signal);
+    if signal == libc::SIGPIPE || (job_control && job_control_signal) {
+        libc::SIG_IGN
+    } else {
+        libc::SIG_DFL
+    }
+}
+
+extern "C" fn record(signal: c_int) {
+    PENDING.fetch_or(1 << signal, Ordering::SeqCst);
+}
+
+/// Takes the signals that arrived since the last call, in the order of their
+/// numbers.
+pub fn take_pending() -> Vec<c_int> {
+    let pending = PENDING.swap(0, Ordering::SeqCst);
+    (1..64).filter(|signal| pending & (1 << signal) != 0).collect()
+}
+
+/// Whether a signal is ignored with `trap ''`. Safe to call between fork and
+/// exec.
+pub fn is_ignored(signal: c_int) -> bool {
+    IGNORED.load(Ordering::SeqCst) & (1 << signal) != 0
+}
+
+/// Ignores the signals ignored with `trap ''` again in a child about to run a
+/// program, which must keep ignoring them even where the spawning code resets
+/// them, as it does `SIGPIPE`. Safe to call between fork and exec.
+pub fn ignore_in_child() -> io::Result<()> {
+    let ignored = IGNORED.load(Ordering::SeqCst);
+    for signal in (1..64).filter(|signal| ignored & (1 << signal) != 0) {
+        if unsafe { libc::signal(signal, libc::SIG_IGN) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn condition_should_parse_names_numbers_and_print_canonical_names() {
+        let test_cases = vec![
+            ("EXIT", Some("EXIT")),
+            ("0", Some("EXIT")),
+            ("err", Some("ERR")),
+            ("INT", Some("SIGINT")),
+            ("sigterm", Some("SIGTERM")),
+            ("15", Some("SIGTERM")),
+            ("FOO", None),
+            ("99", None),
+        ];
+        for (test_case, expected_result) in test_cases {
+            let condition = Condition::parse(test_case).map(|c| c.to_string());
+            assert_eq!(condition.as_deref(), expected_result);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn shell() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_codecrafters-shell"))
+}
+
+/// Runs the shell with `args`, feeding it `stdin` through a pipe.
+fn run(args: &[&str], stdin: &str) -> Output {
+    let mut child = shell()
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn command_string_should_set_the_shell_name_and_positional_parameters() {
+    let output = run(&["-c", "echo $0 $1 $2 $#", "name", "a", "b"], "");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "name a b 2\n");
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = run(&["-c", "echo one; exit 3; echo two"], "");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "one\n");
+    assert_eq!(output.status.code(), Some(3));
+
+    let output = run(&["-c"], "");
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn piped_stdin_should_run_without_a_prompt_and_exit_with_the_last_status() {
+    let output = run(&[], "echo $-\necho second\nfalse\n");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "\nsecond\n");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn piped_stdin_should_leave_unread_input_to_the_commands() {
+    let output = run(&[], "read x\nhello\necho $x\n");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "hello\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+/// Whether `signal` is in the `SigIgn` mask of a `/proc/self/status` listing.
+fn ignores(status: &str, signal: u32) -> bool {
+    let mask = status.lines().find_map(|line| line.strip_prefix("SigIgn:")).unwrap();
+    u64::from_str_radix(mask.trim(), 16).unwrap() & (1 << (signal - 1)) != 0
+}
+
+#[test]
+fn signals_ignored_with_trap_should_stay_ignored_in_programs() {
+    let output = run(&["-c", "trap '' PIPE; yes | head -1; grep SigIgn /proc/self/status"], "");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("y\n"));
+    assert!(ignores(&stdout, 13));
+
+    let output = run(&["-c", "grep SigIgn /proc/self/status"], "");
+    assert!(!ignores(&String::from_utf8_lossy(&output.stdout), 13));
+}
+
+#[test]
+fn exit_should_write_its_errors_through_the_redirections() {
+    let output = run(&["-c", "exit foo 2>/dev/null"], "");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+    assert_eq!(output.status.code(), Some(2));
+
+    let output = run(&["-c", "exit foo 2>&1"], "");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "exit: foo: numeric argument required\n");
+}
+
+#[test]
+fn directories_should_fail_to_run_without_ending_the_shell() {
+    let output = run(&[], "./..\necho $?\necho | /tmp\necho $?\n");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "126\n126\n");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "./..: Is a directory\n/tmp: Is a directory\n"
+    );
+
+    let output = run(&["-c", "/bin/sh -c 'echo $0'"], "");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "/bin/sh\n");
+}